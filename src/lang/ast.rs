@@ -1,9 +1,15 @@
-use super::token::TokenKind;
+use super::token::{TokenKind, Span};
 
 pub type ExprList = Vec<Expr>;
 
 #[derive(Debug)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
     BinOp {
         op: TokenKind,
         left: Box<Expr>,
@@ -15,11 +21,59 @@ pub enum Expr {
     },
 
     Ident(Ident),
+    Integer(i64),
     Number(f64),
-    String(String),
+    // the bytes of the string, which need not be utf-8
+    String(Vec<u8>),
     Boolean(bool),
+    Nil,
+
+    FuncCall(FuncCall),
+    // keeps only the first value of a call
+    Paren(Box<Expr>),
+    Function {
+        args: IdentList,
+        // whether `...` ends the parameters
+        is_vararg: bool,
+        body: StmtList
+    },
+    // `...`, the extra arguments of a vararg function
+    Vararg,
+
+    // `obj.name` is `obj["name"]`
+    Index {
+        obj: Box<Expr>,
+        key: Box<Expr>
+    },
+    Table(Vec<Field>)
+}
+
+// a chain like `a + b + c` or `a.b.c` nests as deep as it is long,
+// so it is taken apart in a loop rather than dropped by recursing
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut kind = std::mem::replace(&mut self.kind, ExprKind::Nil);
+
+        loop {
+            let mut node = match kind {
+                ExprKind::BinOp { left, .. } => left,
+                ExprKind::Index { obj, .. } => obj,
+                ExprKind::FuncCall(call) => call.func,
 
-    FuncCall(FuncCall)
+                _ => break
+            };
+
+            kind = std::mem::replace(&mut node.kind, ExprKind::Nil);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Field {
+    // a value without a key, the keys of which are 1, 2, 3 ...
+    Item(Expr),
+    // `name = value` or `[key] = value`
+    Pair(Expr, Expr)
 }
 
 
@@ -27,23 +81,43 @@ pub type IdentList = Vec<Ident>;
 
 #[derive(Debug)]
 pub struct Ident {
-    pub name: String
+    pub name: String,
+    pub span: Span
 }
 
 
 #[derive(Debug)]
 pub struct FuncCall {
+    pub func: Box<Expr>,
+    // `obj:method(args)` calls `obj.method(obj, args)`, evaluating `obj` once
+    pub method: Option<Ident>,
+    pub args: ExprList,
+    pub span: Span
+}
+
+
+// the name in `function a.b.c:m()`, the fields and the method are optional
+#[derive(Debug)]
+pub struct FuncName {
     pub ident: Ident,
-    pub args: ExprList
+    pub fields: Vec<Ident>,
+    pub method: Option<Ident>
 }
 
 
 pub type StmtList = Vec<Stmt>;
 
 #[derive(Debug)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span
+}
+
+#[derive(Debug)]
+pub enum StmtKind {
+    // the targets are identifiers or indexing expressions
     Assign {
-        ident_list: IdentList,
+        targets: ExprList,
         expr_list: ExprList
     },
     If {
@@ -57,9 +131,43 @@ pub enum Stmt {
         cond: Expr,
         body: StmtList
     },
+    // the condition is in the scope of the body
+    Repeat {
+        body: StmtList,
+        cond: Expr
+    },
+    Do(StmtList),
+    NumericFor {
+        var: Ident,
+        start: Box<Expr>,
+        limit: Box<Expr>,
+        step: Option<Box<Expr>>,
+        body: StmtList
+    },
+    GenericFor {
+        vars: IdentList,
+        exprs: ExprList,
+        body: StmtList
+    },
     FuncDecl {
+        name: FuncName,
+        args: IdentList,
+        is_vararg: bool,
+        body: StmtList
+    },
+    FuncCall(FuncCall),
+    Return(ExprList),
+    Break,
+    Goto(Ident),
+    Label(Ident),
+    Local {
+        ident_list: IdentList,
+        expr_list: ExprList
+    },
+    LocalFuncDecl {
         ident: Ident,
         args: IdentList,
+        is_vararg: bool,
         body: StmtList
     }
 }
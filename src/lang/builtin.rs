@@ -0,0 +1,217 @@
+use std::{cell::RefCell, collections::HashMap, fmt, io::{self, Write}, rc::Rc};
+
+use super::{vm::Value, table::Table, number::Number};
+
+// a function implemented in rust, it gets the arguments of the call
+// and gives back its results or the message of the error it raised
+pub type BuiltinFn = fn(Vec<Value>) -> Result<Vec<Value>, String>;
+
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub func: BuiltinFn
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Builtin({})", self.name)
+    }
+}
+
+// puts the standard functions into the globals
+pub fn load(memory: &mut HashMap<String, Value>) {
+    let builtins: [Builtin; 6] = [
+        Builtin { name: "print", func: print },
+        Builtin { name: "tostring", func: tostring },
+        Builtin { name: "next", func: next },
+        Builtin { name: "pairs", func: pairs },
+        Builtin { name: "ipairs", func: ipairs },
+        Builtin { name: "select", func: select }
+    ];
+
+    for b in builtins {
+        memory.insert(b.name.to_string(), Value::Builtin(b));
+    }
+
+    let math: [Builtin; 1] = [
+        Builtin { name: "type", func: math_type }
+    ];
+    memory.insert("math".to_string(), library(&math));
+
+    let table: [Builtin; 2] = [
+        Builtin { name: "pack", func: table_pack },
+        Builtin { name: "unpack", func: table_unpack }
+    ];
+    memory.insert("table".to_string(), library(&table));
+}
+
+fn library(builtins: &[Builtin]) -> Value {
+    let mut t = Table::new();
+
+    for b in builtins {
+        t.set(b.name.into(), Value::Builtin(*b)).unwrap();
+    }
+
+    t.into()
+}
+
+// writes the bytes of the strings as they are
+fn print(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let mut line = args.iter()
+        .map(|v| v.to_bytes())
+        .collect::<Vec<_>>()
+        .join(&b'\t');
+    line.push(b'\n');
+
+    io::stdout().write_all(&line).map_err(|e| e.to_string())?;
+
+    Ok(vec![])
+}
+
+fn tostring(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    match args.first() {
+        Some(val) => Ok(vec![Value::String(val.to_bytes())]),
+        None => Err("bad argument #1 to 'tostring' (value expected)".to_string())
+    }
+}
+
+// the table given as the argument n, counted from 1
+fn table_arg(args: &[Value], n: usize, name: &str) -> Result<Rc<RefCell<Table>>, String> {
+    match args.get(n - 1) {
+        Some(Value::Table(t)) => Ok(t.clone()),
+
+        arg => {
+            let got = arg.map_or("no value", |v| v.type_name());
+            Err(format!("bad argument #{} to '{}' (table expected, got {})", n, name, got))
+        }
+    }
+}
+
+fn next(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let t = table_arg(&args, 1, "next")?;
+    let key = args.get(1).cloned().unwrap_or(Value::Nil);
+
+    let res = match t.borrow().next(&key)? {
+        Some((key, val)) => vec![key, val],
+        None => vec![Value::Nil]
+    };
+
+    Ok(res)
+}
+
+fn pairs(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let t = table_arg(&args, 1, "pairs")?;
+
+    Ok(vec![
+        Value::Builtin(Builtin { name: "next", func: next }),
+        Value::Table(t),
+        Value::Nil
+    ])
+}
+
+fn ipairs(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let t = table_arg(&args, 1, "ipairs")?;
+
+    Ok(vec![
+        Value::Builtin(Builtin { name: "ipairs_aux", func: ipairs_aux }),
+        Value::Table(t),
+        Value::Integer(0)
+    ])
+}
+
+// the iterator of ipairs, which stops at the first nil
+fn ipairs_aux(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let t = table_arg(&args, 1, "ipairs_aux")?;
+    let i = match args.get(1) {
+        Some(Value::Integer(i)) => i.wrapping_add(1),
+        _ => return Err("bad argument #2 to 'ipairs_aux' (number expected)".to_string())
+    };
+
+    let val = t.borrow().get(&Value::Integer(i));
+    match val {
+        Value::Nil => Ok(vec![Value::Nil]),
+        val => Ok(vec![Value::Integer(i), val])
+    }
+}
+
+// the integer given as the argument n, or `default` when it is absent or nil
+fn int_arg(args: &[Value], n: usize, name: &str, default: Option<i64>) -> Result<i64, String> {
+    let arg = args.get(n - 1).unwrap_or(&Value::Nil);
+
+    if let (Value::Nil, Some(default)) = (arg, default) {
+        return Ok(default);
+    }
+
+    match arg.to_arith_number() {
+        Some(Number::Integer(x)) => Ok(x),
+        Some(Number::Float(x)) if x.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&x) => Ok(x as i64),
+        Some(_) => Err(format!("bad argument #{} to '{}' (number has no integer representation)", n, name)),
+
+        None => {
+            let got = args.get(n - 1).map_or("no value", |v| v.type_name());
+            Err(format!("bad argument #{} to '{}' (number expected, got {})", n, name, got))
+        }
+    }
+}
+
+// select('#', ...) gives the number of the other arguments, and select(n, ...)
+// gives them from the nth on, counting from the end when n is negative
+fn select(mut args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let count = args.len().saturating_sub(1) as i64;
+
+    if let Some(Value::String(x)) = args.first() {
+        if x == b"#" {
+            return Ok(vec![Value::Integer(count)]);
+        }
+    }
+
+    let n = int_arg(&args, 1, "select", None)?;
+    let start = if n < 0 { count + n } else { n - 1 };
+    if n == 0 || start < 0 {
+        return Err("bad argument #1 to 'select' (index out of range)".to_string());
+    }
+
+    Ok(args.split_off((start.min(count) + 1) as usize))
+}
+
+// the arguments in a table, with their number in the field "n"
+fn table_pack(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let mut t = Table::new();
+    let n = args.len() as i64;
+
+    for (i, val) in args.into_iter().enumerate() {
+        t.set(Value::Integer(i as i64 + 1), val)?;
+    }
+    t.set("n".into(), Value::Integer(n))?;
+
+    Ok(vec![t.into()])
+}
+
+// the items of a table from i to j, which default to 1 and its length
+fn table_unpack(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let t = table_arg(&args, 1, "unpack")?;
+    let t = t.borrow();
+
+    let i = int_arg(&args, 2, "unpack", Some(1))?;
+    let j = int_arg(&args, 3, "unpack", Some(t.len()))?;
+    if i > j {
+        return Ok(vec![]);
+    }
+    if (j as i128 - i as i128) >= 1_000_000 {
+        return Err("too many results to unpack".to_string());
+    }
+
+    Ok((i..=j).map(|k| t.get(&Value::Integer(k))).collect())
+}
+
+fn math_type(args: Vec<Value>) -> Result<Vec<Value>, String> {
+    let res = match args.first() {
+        Some(Value::Integer(_)) => "integer".into(),
+        Some(Value::Number(_)) => "float".into(),
+
+        Some(_) => Value::Nil,
+        None => return Err("bad argument #1 to 'type' (value expected)".to_string())
+    };
+
+    Ok(vec![res])
+}
@@ -1,3 +1,7 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use super::{token::Span, number::Number};
+
 #[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     LoadNumber,
@@ -6,6 +10,19 @@ pub enum Instruction {
     StoreGlob,
     LoadLocal,
     StoreLocal,
+    LoadUpvalue,
+    StoreUpvalue,
+    CloseUpvalues,
+    LoadVararg,
+    NewTable,
+    GetIndex,
+    SetIndex,
+    GetField,
+    SetField,
+    LoadMethod,
+    InitIndex,
+    SetList,
+    SetListVar,
 
     LoadTrue,
     LoadFalse,
@@ -27,16 +44,25 @@ pub enum Instruction {
     BinLt,
     BinLe,
     BinEq,
-    BinAnd,
-    BinOr,
 
     JumpAbsoluteIfFalse,
+    // for `and` and `or`, which keep the left operand when they jump
+    JumpAbsoluteIfFalseOrPop,
+    JumpAbsoluteIfTrueOrPop,
     JumpAbsolute,
+    ForPrep,
+    ForLoop,
+    ForIter,
 
     FuncDecl,
     Return,
     FuncCall,
+    // the last value or argument is a call, whose results are all taken
+    ReturnVar,
+    FuncCallVar,
+    Adjust,
 
+    Pop,
     End
 }
 
@@ -49,7 +75,53 @@ pub struct Bytecode {
 #[derive(Debug)]
 pub struct Bytecodes {
     pub bc: Vec<Bytecode>,
-    pub nums: Vec<f64>,
-    pub strs: Vec<String>,
-    pub idents: Vec<String>
+    // the source span each code was compiled from
+    pub spans: Vec<Span>,
+    pub nums: Vec<Number>,
+    pub strs: Vec<Vec<u8>>,
+    pub idents: Vec<String>,
+
+    // the functions declared inside, loaded by `FuncDecl`
+    pub protos: Vec<Rc<Bytecodes>>,
+    // the number of parameters, which are the first locals
+    pub params: usize,
+    // whether the extra arguments are kept for `...`
+    pub is_vararg: bool,
+    pub upvalues: Vec<UpvalueDesc>,
+
+    // the operands of codes which are variables or constants in the source,
+    // by the position of the code and the index of the operand
+    pub names: HashMap<(usize, usize), VarInfo>
+}
+
+// what an operand is in the source, to name it in the message of a runtime error
+#[derive(Debug, Clone, PartialEq)]
+pub enum VarInfo {
+    Global(String),
+    Local(String),
+    Upvalue(String),
+    Field(String),
+    Method(String),
+    Constant(String)
+}
+
+impl fmt::Display for VarInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarInfo::Global(x) => write!(f, "global '{}'", x),
+            VarInfo::Local(x) => write!(f, "local '{}'", x),
+            VarInfo::Upvalue(x) => write!(f, "upvalue '{}'", x),
+            VarInfo::Field(x) => write!(f, "field '{}'", x),
+            VarInfo::Method(x) => write!(f, "method '{}'", x),
+            VarInfo::Constant(x) => write!(f, "constant '{}'", x)
+        }
+    }
+}
+
+// where a closure finds an upvalue when it is made, either in a local of
+// the enclosing function or in one of the upvalues of that function
+#[derive(Debug, Clone, Copy)]
+pub struct UpvalueDesc {
+    pub in_stack: bool,
+    pub idx: usize
 }
\ No newline at end of file
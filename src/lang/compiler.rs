@@ -1,49 +1,161 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
-use super::{ast::{IdentList, ExprList, Expr, StmtList, Ident, Stmt, FuncCall}, bytecode::{Bytecode, Instruction, Bytecodes}, token::TokenKind};
+use super::{ast::{IdentList, ExprList, Expr, ExprKind, StmtList, Ident, Stmt, StmtKind, FuncCall, FuncName, Field}, bytecode::{Bytecode, Instruction, Bytecodes, UpvalueDesc, VarInfo}, token::{TokenKind, Span}, error::{Error, Result}, number::{Number, ArithOp}};
+
+struct Local {
+    name: String,
+    // whether a nested function uses it as an upvalue
+    captured: bool
+}
+
+impl Local {
+    fn new(name: &str) -> Local {
+        Local { name: name.to_string(), captured: false }
+    }
+}
+
+struct Block {
+    // the number of locals when the block was entered
+    nlocals: usize,
+    // where the labels and the pending gotos of the block start
+    first_label: usize,
+    first_goto: usize,
+    // the end of a loop is the target of its breaks
+    is_loop: bool,
+    // the body of a repeat, whose scope goes on into the condition
+    is_repeat: bool
+}
+
+struct Label {
+    name: String,
+    line: usize,
+    // None for a label at the end of its block, which is placed
+    // once the locals of the block are dropped
+    pos: Option<usize>,
+    nlocals: usize
+}
+
+// a goto whose label is not known yet, `break` is a goto to the end of the loop
+struct Goto {
+    name: String,
+    span: Span,
+    // the first of its three codes, closing the upvalues, popping the locals and jumping
+    pos: usize,
+    // the locals on the stack at the goto
+    stack: usize,
+    // the locals in scope, fewer once the goto leaves their block
+    nlocals: usize
+}
 
 pub struct Compiler {
     codes: Vec<Bytecode>,
+    // the source span of every code
+    spans: Vec<Span>,
+    // the span of the node being compiled
+    span: Span,
+
     num_map: HashMap<String, usize>,
     num_map_ptr: usize,
-    str_map: HashMap<String, usize>,
+    nums: Vec<Number>,
+    str_map: HashMap<Vec<u8>, usize>,
     str_map_ptr: usize,
 
     ident_map: HashMap<String, usize>,
-    ident_map_ptr: usize
+    ident_map_ptr: usize,
+
+    protos: Vec<Rc<Bytecodes>>,
+    // the index of each local is its slot
+    locals: Vec<Local>,
+    params: usize,
+    is_vararg: bool,
+    // the names of the upvalues and where to find them when the function is made
+    upvalues: Vec<(String, UpvalueDesc)>,
+
+    // the operands named in runtime errors
+    names: HashMap<(usize, usize), VarInfo>,
+
+    blocks: Vec<Block>,
+    // the labels visible from the current block
+    labels: Vec<Label>,
+    gotos: Vec<Goto>,
+
+    // the compiler of the enclosing function
+    parent: Option<Box<Compiler>>
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
         Compiler {
             codes: vec![],
+            spans: vec![],
+            span: Span::default(),
             num_map: HashMap::new(),
             num_map_ptr: 0,
+            nums: vec![],
             str_map: HashMap::new(),
             str_map_ptr: 0,
 
             ident_map: HashMap::new(),
-            ident_map_ptr: 0
+            ident_map_ptr: 0,
+
+            protos: vec![],
+            locals: vec![],
+            params: 0,
+            is_vararg: false,
+            upvalues: vec![],
+
+            names: HashMap::new(),
+
+            blocks: vec![],
+            labels: vec![],
+            gotos: vec![],
+
+            parent: None
         }
     }
 
-    pub fn compile(&mut self, node: &StmtList) -> Bytecodes {
-        self.visit_stmt_list(node);
+    // a chunk is a vararg function
+    pub fn compile(&mut self, node: &StmtList) -> Result<Bytecodes> {
+        self.is_vararg = true;
+        self.visit_block(node)?;
+
+        Ok(self.finish(Instruction::End))
+    }
+
+    // compiles the expressions so that their values are left on the stack
+    // when the vm reaches the end, used by the repl to echo them
+    pub fn compile_expr_list(&mut self, node: &ExprList) -> Result<Bytecodes> {
+        self.is_vararg = true;
+
+        for (i, expr) in node.iter().enumerate() {
+            if i == node.len() - 1 && Self::is_multi(expr) {
+                self.visit_multi(expr)?;
+            } else {
+                self.visit_expr(expr)?;
+            }
+        }
 
+        Ok(self.finish(Instruction::End))
+    }
+
+    // the code of a chunk ends with `End`, and that of a function with `Return`
+    fn finish(&mut self, last: Instruction) -> Bytecodes {
         let mut bc = self.codes.clone();
-        bc.push(Bytecode { inst: Instruction::End, arg: 0 });
+        bc.push(Bytecode { inst: last, arg: 0 });
+
+        let mut spans = self.spans.clone();
+        spans.push(self.span);
+
+        let nums = self.nums.clone();
 
         // to make the keys ordered
         // there should be a better method
-        let mut nums = self.num_map.clone().into_iter()
-            .collect::<Vec<_>>();
-        nums.sort_by(|a, b| {
-                a.1.cmp(&b.1)
-        });
-        let nums = nums.into_iter().map(|v| {
-            v.0.parse().unwrap()
-        }).collect();
-        
         let mut strs = self.str_map.clone().into_iter()
             .collect::<Vec<_>>();
         strs.sort_by(|a, b| {
@@ -62,117 +174,696 @@ impl Compiler {
             v.0
         }).collect();
 
-        Bytecodes { bc, nums, strs, idents }
+        Bytecodes {
+            bc,
+            spans,
+            nums,
+            strs,
+            idents,
+            protos: self.protos.clone(),
+            params: self.params,
+            is_vararg: self.is_vararg,
+            upvalues: self.upvalues.iter().map(|u| u.1).collect(),
+            names: self.names.clone()
+        }
+    }
+
+    fn push(&mut self, code: Bytecode) {
+        self.codes.push(code);
+        self.spans.push(self.span);
+    }
+
+    // names an operand of the last code after the expression it comes from,
+    // when that is a variable or a constant
+    fn name_operand(&mut self, operand: usize, expr: &Expr) {
+        let info = match &expr.kind {
+            ExprKind::Ident(ident) => self.ident_info(&ident.name),
+            ExprKind::Index { key, .. } => match &key.kind {
+                ExprKind::String(name) => VarInfo::Field(String::from_utf8_lossy(name).into_owned()),
+                _ => return
+            },
+            ExprKind::String(x) => VarInfo::Constant(String::from_utf8_lossy(x).into_owned()),
+
+            _ => return
+        };
+
+        self.name_last(operand, info);
+    }
+
+    fn name_last(&mut self, operand: usize, info: VarInfo) {
+        self.names.insert((self.codes.len() - 1, operand), info);
+    }
+
+    // what a name already compiled refers to
+    fn ident_info(&self, name: &str) -> VarInfo {
+        if self.find_local(name).is_some() {
+            VarInfo::Local(name.to_string())
+        } else if self.upvalues.iter().any(|u| u.0 == name) {
+            VarInfo::Upvalue(name.to_string())
+        } else {
+            VarInfo::Global(name.to_string())
+        }
     }
 
-    fn visit_stmt_list(&mut self, node: &StmtList) {
-        for stmt in node {
-            self.visit_stmt(stmt);
+    fn visit_stmt_list(&mut self, node: &StmtList) -> Result<()> {
+        for (i, stmt) in node.iter().enumerate() {
+            match &stmt.kind {
+                StmtKind::Label(ident) => {
+                    self.span = stmt.span;
+
+                    // only labels follow a label at the end of a block
+                    let last = node[i + 1..].iter().all(|s| matches!(s.kind, StmtKind::Label(_)));
+                    self.visit_label(ident, last)?;
+                },
+
+                _ => self.visit_stmt(stmt)?
+            }
         }
+
+        Ok(())
     }
 
-    fn visit_stmt(&mut self, node: &Stmt) {
-        match node {
-            Stmt::Assign { ident_list, expr_list } => {
-                self.visit_assign(ident_list, expr_list);
+    // the locals declared in a block are popped at its end
+    fn visit_block(&mut self, node: &StmtList) -> Result<()> {
+        self.enter_block(false);
+        self.visit_stmt_list(node)?;
+        self.leave_block()
+    }
+
+    fn enter_block(&mut self, is_loop: bool) {
+        self.blocks.push(Block {
+            nlocals: self.locals.len(),
+            first_label: self.labels.len(),
+            first_goto: self.gotos.len(),
+            is_loop,
+            is_repeat: false
+        });
+    }
+
+    // drops the locals of the block and places its labels at the end,
+    // the gotos still pending go on to the enclosing block
+    fn leave_block(&mut self) -> Result<()> {
+        let &Block { nlocals, first_label, first_goto, is_loop, .. } = self.blocks.last().unwrap();
+
+        self.drop_locals(nlocals);
+
+        let pos = self.codes.len();
+        for i in first_label..self.labels.len() {
+            if self.labels[i].pos.is_none() {
+                self.labels[i].pos = Some(pos);
+                self.labels[i].nlocals = nlocals;
+                self.solve_gotos(&self.labels[i].name.clone(), pos, nlocals)?;
+            }
+        }
+        if is_loop {
+            self.solve_gotos("break", pos, nlocals)?;
+        }
+
+        for goto in &mut self.gotos[first_goto..] {
+            goto.nlocals = goto.nlocals.min(nlocals);
+        }
+        self.labels.truncate(first_label);
+        self.blocks.pop();
+
+        // nothing is left to jump to at the end of the function
+        match self.gotos.first() {
+            Some(goto) if self.blocks.is_empty() => {
+                let msg = if goto.name == "break" {
+                    format!("break outside a loop at line {}", goto.span.start.line)
+                } else {
+                    format!("no visible label '{}' for <goto> at line {}", goto.name, goto.span.start.line)
+                };
+
+                Err(Error::Compile { span: goto.span, msg })
             },
-            Stmt::If {
+
+            _ => Ok(())
+        }
+    }
+
+    // drops the locals from the slot `start` on, closing the upvalues
+    // made from them so that the closures keep their values
+    fn drop_locals(&mut self, start: usize) {
+        if self.locals[start..].iter().any(|l| l.captured) {
+            self.push(Bytecode { inst: Instruction::CloseUpvalues, arg: start });
+        }
+
+        let count = self.locals.len() - start;
+        if count > 0 {
+            self.push(Bytecode { inst: Instruction::Pop, arg: count });
+        }
+        self.locals.truncate(start);
+    }
+
+    fn visit_label(&mut self, ident: &Ident, last: bool) -> Result<()> {
+        if let Some(label) = self.labels.iter().find(|l| l.name == ident.name) {
+            return Err(Error::Compile {
+                span: ident.span,
+                msg: format!("label '{}' already defined on line {}", ident.name, label.line)
+            });
+        }
+
+        // the condition of a repeat comes after the last statement of its body
+        let last = last && !self.blocks.last().unwrap().is_repeat;
+        let pos = if last { None } else { Some(self.codes.len()) };
+
+        self.labels.push(Label {
+            name: ident.name.clone(),
+            line: ident.span.start.line,
+            pos,
+            nlocals: self.locals.len()
+        });
+
+        match pos {
+            Some(pos) => self.solve_gotos(&ident.name, pos, self.locals.len()),
+            None => Ok(())
+        }
+    }
+
+    fn visit_goto(&mut self, name: &str, span: Span) -> Result<()> {
+        // a label seen before is jumped to right away
+        let label = self.labels.iter()
+            .find(|l| l.name == name)
+            .and_then(|l| l.pos.map(|pos| (pos, l.nlocals)));
+
+        if let Some((pos, nlocals)) = label {
+            // a local might have been captured by code after the goto,
+            // run before it through a jump back, so the upvalues are always closed
+            let count = self.locals.len() - nlocals;
+            if count > 0 {
+                self.push(Bytecode { inst: Instruction::CloseUpvalues, arg: nlocals });
+                self.push(Bytecode { inst: Instruction::Pop, arg: count });
+            }
+            self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: pos });
+
+            return Ok(());
+        }
+
+        self.gotos.push(Goto {
+            name: name.to_string(),
+            span,
+            pos: self.codes.len(),
+            stack: self.locals.len(),
+            nlocals: self.locals.len()
+        });
+        self.push(Bytecode { inst: Instruction::CloseUpvalues, arg: 0 });
+        self.push(Bytecode { inst: Instruction::Pop, arg: 0 });
+        self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
+
+        Ok(())
+    }
+
+    // patches the pending gotos of the current block to the label at `pos`
+    fn solve_gotos(&mut self, name: &str, pos: usize, nlocals: usize) -> Result<()> {
+        let first = self.blocks.last().unwrap().first_goto;
+
+        let mut i = first;
+        while i < self.gotos.len() {
+            if self.gotos[i].name != name {
+                i += 1;
+                continue;
+            }
+
+            let goto = self.gotos.remove(i);
+            if goto.nlocals < nlocals {
+                return Err(Error::Compile {
+                    span: goto.span,
+                    msg: format!("<goto {}> at line {} jumps into the scope of local '{}'",
+                        name, goto.span.start.line, self.locals[goto.nlocals].name)
+                });
+            }
+
+            self.codes[goto.pos].arg = nlocals;
+            self.codes[goto.pos + 1].arg = goto.stack - nlocals;
+            self.codes[goto.pos + 2].arg = pos;
+        }
+
+        Ok(())
+    }
+
+    fn visit_stmt(&mut self, node: &Stmt) -> Result<()> {
+        self.span = node.span;
+
+        match &node.kind {
+            StmtKind::Assign { targets, expr_list } => {
+                self.visit_assign(targets, expr_list)
+            },
+            StmtKind::If {
                 cond,
                 if_body,
                 elseif_conds,
                 elseif_bodies,
                 else_body
             } => {
-                self.visit_if(cond, if_body, elseif_conds, elseif_bodies, else_body);
+                self.visit_if(cond, if_body, elseif_conds, elseif_bodies, else_body)
+            },
+            StmtKind::While { cond, body } => {
+                self.visit_while(cond, body)
+            },
+            StmtKind::Repeat { body, cond } => {
+                self.visit_repeat(body, cond)
+            },
+            StmtKind::Do(body) => {
+                self.visit_block(body)
             },
-            Stmt::While { cond, body } => {
-                self.visit_while(cond, body);
+            StmtKind::NumericFor { var, start, limit, step, body } => {
+                self.visit_numeric_for(var, start, limit, step.as_deref(), body)
+            },
+            StmtKind::GenericFor { vars, exprs, body } => {
+                self.visit_generic_for(vars, exprs, body)
+            }
+            StmtKind::FuncDecl { name, args, is_vararg, body } => {
+                self.visit_func_decl(name, args, *is_vararg, body)
+            },
+            StmtKind::FuncCall(call) => {
+                self.visit_func_call(call)?;
+
+                // the results of a call statement are dropped
+                self.push(Bytecode { inst: Instruction::Adjust, arg: 0 });
+
+                Ok(())
+            },
+            StmtKind::Return(expr_list) => {
+                self.visit_return(expr_list)
+            },
+            StmtKind::Break => {
+                self.visit_goto("break", node.span)
+            },
+            StmtKind::Goto(ident) => {
+                self.visit_goto(&ident.name, node.span)
+            },
+            // labels are placed by `visit_stmt_list`, which knows what follows them
+            StmtKind::Label(ident) => {
+                self.visit_label(ident, false)
+            },
+            StmtKind::Local { ident_list, expr_list } => {
+                self.visit_local(ident_list, expr_list)
+            },
+            StmtKind::LocalFuncDecl { ident, args, is_vararg, body } => {
+                // the function can see itself
+                self.locals.push(Local::new(&ident.name));
+
+                let proto = self.visit_func_body(args, *is_vararg, body)?;
+                self.push(Bytecode { inst: Instruction::FuncDecl, arg: proto });
+
+                Ok(())
             }
-            Stmt::FuncDecl { ident, args, body } => {
-                self.visit_func_decl(ident, args, body);
+        }
+    }
+
+    fn visit_func_decl(&mut self, name: &FuncName, args: &IdentList, is_vararg: bool, body: &StmtList) -> Result<()> {
+        let span = self.span;
+
+        // `function a.b.c()` is `a.b.c = function () end`, where `a.b` is
+        // evaluated first and the method name is the last field
+        let mut fields = name.fields.iter().chain(name.method.iter()).collect::<Vec<_>>();
+        let last = fields.pop();
+
+        // what `a.b` is, to name it in runtime errors
+        let mut prefix = None;
+
+        if last.is_some() {
+            self.visit_ident(&name.ident, false)?;
+            let mut info = self.ident_info(&name.ident.name);
+
+            for field in fields {
+                self.span = field.span;
+
+                let b = self.make_str_code(Instruction::GetField, field.name.as_bytes());
+                self.push(b);
+                self.name_last(0, info);
+
+                info = VarInfo::Field(field.name.clone());
             }
+            prefix = Some(info);
+        }
+
+        self.span = span;
+        let proto = self.visit_func_body(args, is_vararg, body)?;
+        self.push(Bytecode { inst: Instruction::FuncDecl, arg: proto });
+
+        match last {
+            Some(field) => {
+                let b = self.make_str_code(Instruction::SetField, field.name.as_bytes());
+                self.push(b);
+                if let Some(info) = prefix {
+                    self.name_last(0, info);
+                }
+
+                Ok(())
+            },
+            None => self.visit_ident(&name.ident, true)
         }
     }
 
-    fn visit_func_decl(&mut self, ident: &Ident, args: &IdentList, body: &StmtList) {
-        self.codes.push(Bytecode { inst: Instruction::FuncDecl, arg: self.codes.len() + 1 });
-        self.codes.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
-        let pos = self.codes.len() - 1;
+    // compiles a function into a new proto and gives its index
+    fn visit_func_body(&mut self, args: &IdentList, is_vararg: bool, body: &StmtList) -> Result<usize> {
+        let span = self.span;
 
-        // parsing arguments
-        args.into_iter().rev().for_each(|i| {
-            self.visit_ident(i, Instruction::StoreGlob);
-        });
-        
-        self.visit_stmt_list(body);
-        self.codes[pos].arg = self.codes.len() - 1;
+        // the body gets a compiler of its own, with the parameters as its first locals
+        let parent = std::mem::take(self);
+        self.parent = Some(Box::new(parent));
+        self.locals = args.iter().map(|i| Local::new(&i.name)).collect();
+        self.params = args.len();
+        self.is_vararg = is_vararg;
+
+        let res = self.visit_block(body);
+        self.span = span;
+        let proto = self.finish(Instruction::Return);
+
+        let parent = self.parent.take().unwrap();
+        *self = *parent;
+        res?;
+
+        self.span = span;
+        self.protos.push(Rc::new(proto));
+
+        Ok(self.protos.len() - 1)
+    }
+
+    fn visit_local(&mut self,
+        ident_list: &IdentList,
+        expr_list: &ExprList
+    ) -> Result<()> {
+        let span = self.span;
+
+        // the values are left on the stack, right in the slots of the new locals
+        self.visit_adjusted(expr_list, ident_list.len())?;
+        self.span = span;
+
+        // the locals come into scope after the values are computed
+        for ident in ident_list {
+            self.locals.push(Local::new(&ident.name));
+        }
+
+        Ok(())
+    }
+
+    // leaves exactly `n` values on the stack, dropping the extra ones or
+    // filling with nil, a call at the end gives as many values as needed
+    fn visit_adjusted(&mut self, expr_list: &ExprList, n: usize) -> Result<()> {
+        let span = self.span;
+
+        for (i, expr) in expr_list.iter().enumerate() {
+            if i == expr_list.len() - 1 && i < n && Self::is_multi(expr) {
+                self.visit_multi(expr)?;
+                self.push(Bytecode { inst: Instruction::Adjust, arg: n - i });
+
+                return Ok(());
+            }
+
+            self.visit_expr(expr)?;
+
+            if i >= n {
+                self.push(Bytecode { inst: Instruction::Pop, arg: 1 });
+            }
+        }
+
+        self.span = span;
+        for _ in expr_list.len()..n {
+            self.push(Bytecode { inst: Instruction::LoadNil, arg: 0 });
+        }
+
+        Ok(())
+    }
+
+    fn visit_return(&mut self, expr_list: &ExprList) -> Result<()> {
+        let span = self.span;
+
+        for (i, expr) in expr_list.iter().enumerate() {
+            if i == expr_list.len() - 1 && Self::is_multi(expr) {
+                self.visit_multi(expr)?;
+                self.span = span;
+                self.push(Bytecode { inst: Instruction::ReturnVar, arg: expr_list.len() - 1 });
+
+                return Ok(());
+            }
+
+            self.visit_expr(expr)?;
+        }
+
+        self.span = span;
+        self.push(Bytecode { inst: Instruction::Return, arg: expr_list.len() });
+
+        Ok(())
+    }
+
+    // whether the expression gives any number of values
+    fn is_multi(expr: &Expr) -> bool {
+        matches!(expr.kind, ExprKind::FuncCall(_) | ExprKind::Vararg)
+    }
+
+    // leaves all the values of a multi-valued expression on the stack,
+    // counted by `nres` in the vm
+    fn visit_multi(&mut self, expr: &Expr) -> Result<()> {
+        self.span = expr.span;
+
+        match &expr.kind {
+            ExprKind::FuncCall(call) => self.visit_func_call(call),
+            ExprKind::Vararg => self.visit_vararg(),
+
+            _ => unreachable!()
+        }
     }
 
     fn visit_while(&mut self,
         cond: &Expr,
         body: &StmtList
-    ) {
-        let cond_pos = self.codes.len() - 1;
-        self.visit_expr(cond);
-        self.codes.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: 0 });
+    ) -> Result<()> {
+        self.enter_block(true);
+
+        let cond_pos = self.codes.len();
+        self.visit_expr(cond)?;
+        self.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: 0 });
+        let jmp_pos = self.codes.len() - 1;
+
+        self.visit_block(body)?;
+        self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: cond_pos });
+        self.codes[jmp_pos].arg = self.codes.len();
+
+        self.leave_block()
+    }
+
+    fn visit_repeat(&mut self,
+        body: &StmtList,
+        cond: &Expr
+    ) -> Result<()> {
+        let span = self.span;
+        self.enter_block(true);
+
+        let body_pos = self.codes.len();
+        self.enter_block(false);
+        self.blocks.last_mut().unwrap().is_repeat = true;
+        let start = self.locals.len();
+
+        self.visit_stmt_list(body)?;
+        self.visit_expr(cond)?;
+        self.span = span;
+
+        let captured = self.locals[start..].iter().any(|l| l.captured);
+        let count = self.locals.len() - start;
+
+        self.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: body_pos });
         let jmp_pos = self.codes.len() - 1;
+        self.leave_block()?;
 
-        self.visit_stmt_list(body);
-        self.codes.push(Bytecode { inst: Instruction::JumpAbsolute, arg: cond_pos });
-        self.codes[jmp_pos].arg = self.codes.len() - 1;
+        // the locals of the body are dropped on the way back as well
+        if count > 0 {
+            self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
+            let exit_pos = self.codes.len() - 1;
+
+            self.codes[jmp_pos].arg = self.codes.len();
+            if captured {
+                self.push(Bytecode { inst: Instruction::CloseUpvalues, arg: start });
+            }
+            self.push(Bytecode { inst: Instruction::Pop, arg: count });
+            self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: body_pos });
+
+            self.codes[exit_pos].arg = self.codes.len();
+        }
+
+        self.leave_block()
+    }
+
+    fn visit_numeric_for(&mut self,
+        var: &Ident,
+        start: &Expr,
+        limit: &Expr,
+        step: Option<&Expr>,
+        body: &StmtList
+    ) -> Result<()> {
+        let span = self.span;
+        self.enter_block(true);
+
+        // the state of the loop lives in three hidden locals
+        self.visit_expr(start)?;
+        self.visit_expr(limit)?;
+        match step {
+            Some(step) => self.visit_expr(step)?,
+            None => {
+                self.span = span;
+
+                let b = self.make_num_code(Instruction::LoadNumber, Number::Integer(1));
+                self.push(b);
+            }
+        }
+        self.locals.extend(["(for index)", "(for limit)", "(for step)"].map(Local::new));
+
+        self.span = span;
+        self.push(Bytecode { inst: Instruction::ForPrep, arg: 0 });
+        let prep_pos = self.codes.len() - 1;
+
+        // every iteration gets a fresh copy of the index as the variable
+        let body_pos = self.codes.len();
+        self.enter_block(false);
+        self.locals.push(Local::new(&var.name));
+        self.visit_stmt_list(body)?;
+        self.span = span;
+        self.leave_block()?;
+
+        self.push(Bytecode { inst: Instruction::ForLoop, arg: body_pos });
+        self.codes[prep_pos].arg = self.codes.len();
+
+        self.leave_block()
+    }
+
+    fn visit_generic_for(&mut self,
+        vars: &IdentList,
+        exprs: &ExprList,
+        body: &StmtList
+    ) -> Result<()> {
+        let span = self.span;
+        let outer = self.locals.len();
+        self.enter_block(true);
+
+        // the iterator, the state and the control variable
+        self.visit_adjusted(exprs, 3)?;
+        self.locals.extend(["(for iterator)", "(for state)", "(for control)"].map(Local::new));
+
+        // every iteration calls the iterator with the state and the control variable
+        let loop_pos = self.codes.len();
+        for slot in outer..outer + 3 {
+            self.push(Bytecode { inst: Instruction::LoadLocal, arg: slot });
+        }
+        self.push(Bytecode { inst: Instruction::FuncCall, arg: 2 });
+        self.push(Bytecode { inst: Instruction::Adjust, arg: vars.len() });
+
+        let inner = self.locals.len();
+        self.enter_block(false);
+        self.locals.extend(vars.iter().map(|v| Local::new(&v.name)));
+
+        // the first variable becomes the control variable, unless it is nil
+        self.push(Bytecode { inst: Instruction::LoadLocal, arg: inner });
+        self.push(Bytecode { inst: Instruction::ForIter, arg: 0 });
+        let iter_pos = self.codes.len() - 1;
+        self.push(Bytecode { inst: Instruction::StoreLocal, arg: outer + 2 });
+
+        self.visit_stmt_list(body)?;
+        self.span = span;
+        self.leave_block()?;
+        self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: loop_pos });
+
+        // the loop ends with the variables still on the stack
+        self.codes[iter_pos].arg = self.codes.len();
+        self.push(Bytecode { inst: Instruction::Pop, arg: vars.len() });
+
+        self.leave_block()
     }
 
     fn visit_if(&mut self,
         cond: &Expr,
         if_body: &StmtList,
-        elseif_conds: &Vec<Box<Expr>>,
-        elseif_bodies: &Vec<StmtList>,
+        elseif_conds: &[Box<Expr>],
+        elseif_bodies: &[StmtList],
         else_body: &StmtList
-    ) {
-        self.visit_expr(cond);
-        self.codes.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: 0 });
+    ) -> Result<()> {
+        self.visit_expr(cond)?;
+        self.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: 0 });
         let if_pos = self.codes.len() - 1;
         
-        self.visit_stmt_list(if_body);
-        self.codes.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
-        self.codes[if_pos].arg = self.codes.len() - 1;
+        self.visit_block(if_body)?;
+        self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
+        self.codes[if_pos].arg = self.codes.len();
 
         let mut s_pos = vec![self.codes.len() - 1];
 
-        elseif_conds.iter().zip(elseif_bodies.iter())
-            .for_each(|(cond, body)| {
-                self.visit_expr(cond);
-                self.codes.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: 0 });
-                let if_pos = self.codes.len() - 1;
-                
-                self.visit_stmt_list(body);
-                self.codes.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
-                self.codes[if_pos].arg = self.codes.len() - 1;
-                s_pos.push(self.codes.len() - 1);
-            });
+        for (cond, body) in elseif_conds.iter().zip(elseif_bodies.iter()) {
+            self.visit_expr(cond)?;
+            self.push(Bytecode { inst: Instruction::JumpAbsoluteIfFalse, arg: 0 });
+            let if_pos = self.codes.len() - 1;
+            
+            self.visit_block(body)?;
+            self.push(Bytecode { inst: Instruction::JumpAbsolute, arg: 0 });
+            self.codes[if_pos].arg = self.codes.len();
+            s_pos.push(self.codes.len() - 1);
+        }
 
-        self.visit_stmt_list(else_body);
+        self.visit_block(else_body)?;
         
         for i in s_pos {
-            self.codes[i].arg = self.codes.len() - 1;
+            self.codes[i].arg = self.codes.len();
         }
+
+        Ok(())
     }
 
     fn visit_assign(&mut self,
-        ident_list: &IdentList,
+        targets: &ExprList,
         expr_list: &ExprList
-    ) {
-        for expr in expr_list {
-            self.visit_expr(expr);
+    ) -> Result<()> {
+        // the tables and keys of the targets are evaluated before the values
+        for target in targets {
+            if let ExprKind::Index { obj, key } = &target.kind {
+                self.visit_expr(obj)?;
+                self.visit_expr(key)?;
+            }
         }
-        for ident in ident_list.iter().rev() {
-            self.visit_ident(ident, Instruction::StoreGlob);
+
+        self.visit_adjusted(expr_list, targets.len())?;
+
+        // the values are stored from the last one, so the table and key of a
+        // target are right below the values of the targets before it
+        for (i, target) in targets.iter().enumerate().rev() {
+            match &target.kind {
+                ExprKind::Ident(ident) => self.visit_ident(ident, true)?,
+                ExprKind::Index { obj, .. } => {
+                    self.span = target.span;
+                    self.push(Bytecode { inst: Instruction::SetIndex, arg: i });
+                    self.name_operand(0, obj);
+                },
+                _ => unreachable!()
+            }
         }
+
+        Ok(())
     }
 
-    fn visit_ident(&mut self, ident: &Ident, inst: Instruction) {
+    // loads or stores a variable, which is a local if one has the name
+    // and a global otherwise
+    fn visit_ident(&mut self, ident: &Ident, store: bool) -> Result<()> {
         let val = ident.name.clone();
+        self.span = ident.span;
 
+        // the latest local shadows the others
+        if let Some(slot) = self.find_local(&val) {
+            self.push(Bytecode {
+                inst: if store { Instruction::StoreLocal } else { Instruction::LoadLocal },
+                arg: slot
+            });
+
+            return Ok(());
+        }
+
+        if let Some(idx) = self.find_upvalue(&val) {
+            self.push(Bytecode {
+                inst: if store { Instruction::StoreUpvalue } else { Instruction::LoadUpvalue },
+                arg: idx
+            });
+
+            return Ok(());
+        }
+
+        let inst = if store { Instruction::StoreGlob } else { Instruction::LoadGlob };
         let arg = if self.ident_map.contains_key(&val) {
             *self.ident_map.get(&val).unwrap()
         } else {
@@ -182,93 +873,74 @@ impl Compiler {
             arg
         };
 
-        self.codes.push(Bytecode {
-            inst: inst,
+        self.push(Bytecode {
+            inst,
             arg
         });
+
+        Ok(())
     }
 
-    fn visit_expr(&mut self, expr: &Expr) {
-        match expr {
-            Expr::BinOp { op, left, right } => {
-                self.visit_expr(left);
-                self.visit_expr(right);
-                
-                match op {
-                    TokenKind::Plus => self.codes.push(Bytecode {
-                        inst: Instruction::BinAdd,
-                        arg: 0
-                    }),
-                    TokenKind::Minus => self.codes.push(Bytecode {
-                        inst: Instruction::BinMinus,
-                        arg: 0
-                    }),
-                    TokenKind::Mul => self.codes.push(Bytecode {
-                        inst: Instruction::BinMul,
-                        arg: 0
-                    }),
-                    TokenKind::RealDiv => self.codes.push(Bytecode {
-                        inst: Instruction::BinRealDiv,
-                        arg: 0
-                    }),
-                    TokenKind::IntDiv => self.codes.push(Bytecode {
-                        inst: Instruction::BinIntDiv,
-                        arg: 0
-                    }),
-                    TokenKind::Mod => self.codes.push(Bytecode {
-                        inst: Instruction::BinMod,
-                        arg: 0
-                    }),
-                    TokenKind::Concat => self.codes.push(Bytecode {
-                        inst: Instruction::BinConcat,
-                        arg: 0
-                    }),
-                    TokenKind::Pow => self.codes.push(Bytecode {
-                        inst: Instruction::BinPow,
-                        arg: 0
-                    }),
+    fn visit_expr(&mut self, expr: &Expr) -> Result<()> {
+        self.span = expr.span;
 
-                    TokenKind::Lt => self.codes.push(Bytecode {
-                        inst: Instruction::BinLt,
-                        arg: 0
-                    }),
+        // operations on numeric constants are done right now
+        if let ExprKind::UnaryOp { .. } = expr.kind {
+            if let Some(x) = Self::fold(expr) {
+                let b = self.make_num_code(Instruction::LoadNumber, x);
+                self.push(b);
 
-                    _ => panic!("BinOP???")
-                }
+                return Ok(());
+            }
+        }
+
+        match &expr.kind {
+            ExprKind::BinOp { .. } => {
+                self.visit_bin_op(expr)?;
             },
 
-            Expr::UnaryOp { op, node } => {
-                self.visit_expr(node);
+            ExprKind::UnaryOp { op, node } => {
+                self.visit_expr(node)?;
+                self.span = expr.span;
 
                 match op {
-                    TokenKind::Not => self.codes.push(Bytecode {
+                    TokenKind::Not => self.push(Bytecode {
                         inst: Instruction::UnaryNot,
                         arg: 0
                     }),
-                    TokenKind::Minus => self.codes.push(Bytecode {
+                    TokenKind::Minus => self.push(Bytecode {
                         inst: Instruction::UnaryMinus,
                         arg: 0
                     }),
-                    TokenKind::Len => self.codes.push(Bytecode {
+                    TokenKind::Len => self.push(Bytecode {
                         inst: Instruction::UnaryLen,
                         arg: 0
                     }),
 
-                    _ => panic!("UnaryOP???")
+                    _ => return Err(Error::Compile {
+                        span: expr.span,
+                        msg: format!("unsupported unary operator {}", op)
+                    })
                 }
+
+                self.name_operand(0, node);
             },
 
-            Expr::Number(x) => {
-                let b = self.make_num_code(Instruction::LoadNumber, *x);
-                self.codes.push(b);
+            ExprKind::Integer(x) => {
+                let b = self.make_num_code(Instruction::LoadNumber, Number::Integer(*x));
+                self.push(b);
+            },
+            ExprKind::Number(x) => {
+                let b = self.make_num_code(Instruction::LoadNumber, Number::Float(*x));
+                self.push(b);
             },
 
-            Expr::Ident(x) => {
-                self.visit_ident(x, Instruction::LoadGlob);
+            ExprKind::Ident(x) => {
+                self.visit_ident(x, false)?;
             },
 
-            Expr::Boolean(x) => {
-                self.codes.push(Bytecode {
+            ExprKind::Boolean(x) => {
+                self.push(Bytecode {
                     inst: if *x {
                         Instruction::LoadTrue
                     } else {
@@ -277,37 +949,410 @@ impl Compiler {
                     arg: 0
                 });
             },
+            ExprKind::Nil => {
+                self.push(Bytecode { inst: Instruction::LoadNil, arg: 0 });
+            },
+
+            ExprKind::FuncCall(call) => {
+                self.visit_func_call(call)?;
+                self.push(Bytecode { inst: Instruction::Adjust, arg: 1 });
+            },
+            ExprKind::Paren(node) => {
+                self.visit_expr(node)?;
+            },
+            ExprKind::Vararg => {
+                self.visit_vararg()?;
+                self.push(Bytecode { inst: Instruction::Adjust, arg: 1 });
+            },
+            ExprKind::Function { args, is_vararg, body } => {
+                let proto = self.visit_func_body(args, *is_vararg, body)?;
+                self.push(Bytecode { inst: Instruction::FuncDecl, arg: proto });
+            },
 
-            Expr::FuncCall(FuncCall {
-                ident,
-                args
-            }) => {
-                self.visit_ident(ident, Instruction::LoadGlob);
+            ExprKind::Index { .. } => {
+                self.visit_suffixed(expr)?;
+            },
+            ExprKind::Table(fields) => {
+                self.visit_table(fields)?;
+            },
 
-                args.into_iter().for_each(|e| {
-                    self.visit_expr(e);
-                });
+            ExprKind::String(x) => {
+                let b = self.make_str_code(Instruction::LoadString, x);
+                self.push(b);
+            }
+        }
+
+        Ok(())
+    }
+
+    // compiles a chain of operations like `a + b + c`, which nest in their
+    // left operands, in a loop from the innermost one, so that the length
+    // of the chain is not limited by the stack
+    fn visit_bin_op(&mut self, expr: &Expr) -> Result<()> {
+        let (first, chain) = Self::bin_op_chain(expr);
+
+        // the operations on numeric constants at the start are done right now
+        let mut done = 0;
+        if let Some(mut x) = Self::fold(first) {
+            for (node, op, _, right) in &chain {
+                match Self::fold(right).and_then(|y| Self::fold_arith(*op, x, y)) {
+                    Some(res) => x = res,
+                    None => break
+                }
+
+                self.span = node.span;
+                done += 1;
+            }
+
+            if done > 0 {
+                let b = self.make_num_code(Instruction::LoadNumber, x);
+                self.push(b);
+            }
+        }
+
+        if done == 0 {
+            self.visit_expr(first)?;
+        }
+
+        for &(node, op, left, right) in &chain[done..] {
+            self.visit_operator(node, op, left, right)?;
+        }
+
+        Ok(())
+    }
+
+    // the operations of a chain like `a + b + c` from the innermost one,
+    // each with its operator and operands, and the operand it starts from
+    fn bin_op_chain(expr: &Expr) -> (&Expr, Vec<(&Expr, TokenKind, &Expr, &Expr)>) {
+        let mut chain = vec![];
+        let mut node = expr;
+
+        while let ExprKind::BinOp { op, left, right } = &node.kind {
+            chain.push((node, *op, &**left, &**right));
+            node = left;
+        }
+        chain.reverse();
+
+        (node, chain)
+    }
+
+    // compiles an operation whose left operand is already on the stack
+    fn visit_operator(&mut self, expr: &Expr, op: TokenKind, left: &Expr, right: &Expr) -> Result<()> {
+        self.span = expr.span;
+
+        // the right operand is only evaluated when the left one does not decide
+        if let TokenKind::And | TokenKind::Or = op {
+            let inst = if op == TokenKind::And {
+                Instruction::JumpAbsoluteIfFalseOrPop
+            } else {
+                Instruction::JumpAbsoluteIfTrueOrPop
+            };
+            self.push(Bytecode { inst, arg: 0 });
+            let jmp_pos = self.codes.len() - 1;
 
-                self.codes.push(Bytecode { inst: Instruction::FuncCall, arg: 0});
+            self.visit_expr(right)?;
+            self.codes[jmp_pos].arg = self.codes.len();
+
+            return Ok(());
+        }
+
+        self.visit_expr(right)?;
+        self.span = expr.span;
+
+        match op {
+                TokenKind::Plus => self.push(Bytecode {
+                    inst: Instruction::BinAdd,
+                    arg: 0
+                }),
+                TokenKind::Minus => self.push(Bytecode {
+                    inst: Instruction::BinMinus,
+                    arg: 0
+                }),
+                TokenKind::Mul => self.push(Bytecode {
+                    inst: Instruction::BinMul,
+                    arg: 0
+                }),
+                TokenKind::RealDiv => self.push(Bytecode {
+                    inst: Instruction::BinRealDiv,
+                    arg: 0
+                }),
+                TokenKind::IntDiv => self.push(Bytecode {
+                    inst: Instruction::BinIntDiv,
+                    arg: 0
+                }),
+                TokenKind::Mod => self.push(Bytecode {
+                    inst: Instruction::BinMod,
+                    arg: 0
+                }),
+                TokenKind::Concat => self.push(Bytecode {
+                    inst: Instruction::BinConcat,
+                    arg: 0
+                }),
+                TokenKind::Pow => self.push(Bytecode {
+                    inst: Instruction::BinPow,
+                    arg: 0
+                }),
+
+                TokenKind::Lt => self.push(Bytecode {
+                    inst: Instruction::BinLt,
+                    arg: 0
+                }),
+                TokenKind::Le => self.push(Bytecode {
+                    inst: Instruction::BinLe,
+                    arg: 0
+                }),
+                // the operands are swapped by the vm
+                TokenKind::Gt => self.push(Bytecode {
+                    inst: Instruction::BinLt,
+                    arg: 1
+                }),
+                TokenKind::Ge => self.push(Bytecode {
+                    inst: Instruction::BinLe,
+                    arg: 1
+                }),
+                TokenKind::Eq => self.push(Bytecode {
+                    inst: Instruction::BinEq,
+                    arg: 0
+                }),
+                TokenKind::UnEq => {
+                    self.push(Bytecode { inst: Instruction::BinEq, arg: 0 });
+                    self.push(Bytecode { inst: Instruction::UnaryNot, arg: 0 });
+                },
+
+                _ => return Err(Error::Compile {
+                    span: expr.span,
+                    msg: format!("unsupported binary operator {}", op)
+                })
+            }
+
+        self.name_operand(0, left);
+        self.name_operand(1, right);
+
+        Ok(())
+    }
+
+    // compiles a chain of suffixes like `a.b[c](d)`, which nest in their
+    // objects, in a loop from the innermost one like `visit_bin_op`
+    fn visit_suffixed(&mut self, expr: &Expr) -> Result<()> {
+        let mut chain = vec![];
+        let mut node = expr;
+
+        while let ExprKind::Index { obj: inner, .. } | ExprKind::FuncCall(FuncCall { func: inner, .. }) = &node.kind {
+            chain.push(node);
+            node = inner;
+        }
+        self.visit_expr(node)?;
+
+        for node in chain.into_iter().rev() {
+            match &node.kind {
+                ExprKind::Index { obj, key } => {
+                    // a constant name needs no key on the stack
+                    if let ExprKind::String(name) = &key.kind {
+                        self.span = node.span;
+
+                        let b = self.make_str_code(Instruction::GetField, name);
+                        self.push(b);
+                    } else {
+                        self.visit_expr(key)?;
+                        self.span = node.span;
+
+                        self.push(Bytecode { inst: Instruction::GetIndex, arg: 0 });
+                    }
+                    self.name_operand(0, obj);
+                },
+                ExprKind::FuncCall(call) => {
+                    self.visit_call_suffix(call)?;
+                    self.push(Bytecode { inst: Instruction::Adjust, arg: 1 });
+                },
+
+                _ => unreachable!()
+            }
+        }
+
+        Ok(())
+    }
+
+    fn visit_vararg(&mut self) -> Result<()> {
+        if !self.is_vararg {
+            return Err(Error::Compile {
+                span: self.span,
+                msg: "cannot use '...' outside a vararg function near '...'".to_string()
+            });
+        }
+
+        self.push(Bytecode { inst: Instruction::LoadVararg, arg: 0 });
+
+        Ok(())
+    }
+
+    fn visit_table(&mut self, fields: &[Field]) -> Result<()> {
+        let span = self.span;
+        self.push(Bytecode { inst: Instruction::NewTable, arg: 0 });
+
+        // the items wait on the stack above the table until the end,
+        // so a pair goes into the table below them
+        let mut items = 0;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                // a call at the end gives all its results as items
+                Field::Item(value) if i == fields.len() - 1 && Self::is_multi(value) => {
+                    self.visit_multi(value)?;
+                    self.span = span;
+                    self.push(Bytecode { inst: Instruction::SetListVar, arg: items });
+
+                    return Ok(());
+                },
+                Field::Item(value) => {
+                    self.visit_expr(value)?;
+                    items += 1;
+                },
+                Field::Pair(key, value) => {
+                    self.visit_expr(key)?;
+                    self.visit_expr(value)?;
+
+                    self.span = key.span.to(value.span);
+                    self.push(Bytecode { inst: Instruction::InitIndex, arg: items });
+                }
+            }
+        }
+
+        self.span = span;
+        if items > 0 {
+            self.push(Bytecode { inst: Instruction::SetList, arg: items });
+        }
+
+        Ok(())
+    }
+
+    fn visit_func_call(&mut self, call: &FuncCall) -> Result<()> {
+        self.visit_suffixed(&call.func)?;
+        self.visit_call_suffix(call)
+    }
+
+    // compiles the method and arguments of a call whose callee is on the stack
+    fn visit_call_suffix(&mut self, call: &FuncCall) -> Result<()> {
+        // the object goes above the method as its first argument
+        let mut argc = call.args.len();
+        if let Some(method) = &call.method {
+            self.span = method.span;
+
+            let b = self.make_str_code(Instruction::LoadMethod, method.name.as_bytes());
+            self.push(b);
+            self.name_operand(0, &call.func);
+            argc += 1;
+        }
+
+        // a call as the last argument passes all its results
+        let mut inst = Instruction::FuncCall;
+        for (i, e) in call.args.iter().enumerate() {
+            if i == call.args.len() - 1 && Self::is_multi(e) {
+                self.visit_multi(e)?;
+
+                inst = Instruction::FuncCallVar;
+                argc -= 1;
+            } else {
+                self.visit_expr(e)?;
+            }
+        }
+        self.span = call.span;
+
+        self.push(Bytecode { inst, arg: argc });
+        match &call.method {
+            Some(method) => self.name_last(0, VarInfo::Method(method.name.clone())),
+            None => self.name_operand(0, &call.func)
+        }
+
+        Ok(())
+    }
+
+    fn find_local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|l| l.name == name)
+    }
+
+    // looks for a local of the enclosing functions, adding the upvalues
+    // needed to pass it down to this one
+    fn find_upvalue(&mut self, name: &str) -> Option<usize> {
+        if let Some(idx) = self.upvalues.iter().position(|u| u.0 == name) {
+            return Some(idx);
+        }
+
+        let parent = self.parent.as_mut()?;
+        let desc = if let Some(slot) = parent.find_local(name) {
+            parent.locals[slot].captured = true;
+
+            UpvalueDesc { in_stack: true, idx: slot }
+        } else {
+            UpvalueDesc { in_stack: false, idx: parent.find_upvalue(name)? }
+        };
+
+        self.upvalues.push((name.to_string(), desc));
+
+        Some(self.upvalues.len() - 1)
+    }
+
+    // evaluates an expression made of numeric constants only,
+    // following a chain of operations in a loop like `visit_bin_op`
+    fn fold(expr: &Expr) -> Option<Number> {
+        let (first, chain) = Self::bin_op_chain(expr);
+
+        let mut x = match &first.kind {
+            ExprKind::Integer(x) => Number::Integer(*x),
+            ExprKind::Number(x) => Number::Float(*x),
+
+            ExprKind::Paren(node) => Self::fold(node)?,
+            ExprKind::UnaryOp { op: TokenKind::Minus, node } => {
+                let x = Self::fold(node)?;
+
+                Self::folded(Number::arith(ArithOp::Neg, x, x)?)?
             },
 
-            _ => unimplemented!()
+            _ => return None
+        };
+
+        for (_, op, _, right) in chain {
+            x = Self::fold_arith(op, x, Self::fold(right)?)?;
         }
 
+        Some(x)
+    }
+
+    fn fold_arith(op: TokenKind, x: Number, y: Number) -> Option<Number> {
+        let op = match op {
+            TokenKind::Plus => ArithOp::Add,
+            TokenKind::Minus => ArithOp::Sub,
+            TokenKind::Mul => ArithOp::Mul,
+            TokenKind::RealDiv => ArithOp::Div,
+            TokenKind::IntDiv => ArithOp::IntDiv,
+            TokenKind::Mod => ArithOp::Mod,
+            TokenKind::Pow => ArithOp::Pow,
+
+            _ => return None
+        };
+
+        Self::folded(Number::arith(op, x, y)?)
+    }
 
+    // like lua, leave NaN and zero floats (which may be -0.0) to the vm
+    fn folded(res: Number) -> Option<Number> {
+        match res {
+            Number::Float(x) if x.is_nan() || x == 0.0 => None,
+            res => Some(res)
+        }
     }
 
-    fn make_num_code(&mut self, inst: Instruction, val: f64) -> Bytecode {
-        let val = val.to_string();
-        if self.num_map.contains_key(&val) {
+    fn make_num_code(&mut self, inst: Instruction, val: Number) -> Bytecode {
+        // the debug format tells 1 from 1.0 and keeps every bit of a float
+        let key = format!("{:?}", val);
+        if self.num_map.contains_key(&key) {
             Bytecode {
                 inst,
-                arg: *self.num_map.get(&val).unwrap()
+                arg: *self.num_map.get(&key).unwrap()
             }
         } else {
             let arg = self.num_map_ptr;
-            self.num_map.insert(val, arg);
+            self.num_map.insert(key, arg);
             self.num_map_ptr += 1;
+            self.nums.push(val);
             
             Bytecode {
                 inst,
@@ -315,6 +1360,24 @@ impl Compiler {
             }
         }
     }
+
+    fn make_str_code(&mut self, inst: Instruction, val: &[u8]) -> Bytecode {
+        if self.str_map.contains_key(val) {
+            Bytecode {
+                inst,
+                arg: *self.str_map.get(val).unwrap()
+            }
+        } else {
+            let arg = self.str_map_ptr;
+            self.str_map.insert(val.to_vec(), arg);
+            self.str_map_ptr += 1;
+
+            Bytecode {
+                inst,
+                arg
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -328,10 +1391,10 @@ mod tests {
         let toks = Lexer::new(r#"
             a = 1 + 3 ^ 4 ^ 2
             b = 6 * (5 - 2)
-        "#).analyze();
+        "#).analyze().unwrap();
         let mut parser = Parser::new(toks);
         let mut compiler = Compiler::new();
-        let res = compiler.compile(&parser.parse());
+        let res = compiler.compile(&parser.parse().unwrap()).unwrap();
 
         println!("{:#?}", res);
     }
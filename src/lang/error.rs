@@ -0,0 +1,130 @@
+use std::fmt;
+
+use super::token::{Location, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Lex {
+        span: Span,
+        msg: String
+    },
+    Parse {
+        span: Span,
+        msg: String
+    },
+    Compile {
+        span: Span,
+        msg: String
+    },
+    Runtime {
+        span: Span,
+        msg: String
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Lex { span, .. } |
+            Error::Parse { span, .. } |
+            Error::Compile { span, .. } |
+            Error::Runtime { span, .. } => *span
+        }
+    }
+
+    pub fn loc(&self) -> Location {
+        self.span().start
+    }
+
+    pub fn msg(&self) -> &str {
+        match self {
+            Error::Lex { msg, .. } |
+            Error::Parse { msg, .. } |
+            Error::Compile { msg, .. } |
+            Error::Runtime { msg, .. } => msg
+        }
+    }
+
+    // formats the error as a diagnostic, with the offending line of `src`
+    // and the span underlined, e.g.
+    //
+    //   script.lua:12:5: expected 'then', found '='
+    //      |
+    //   12 | if a = 1 then
+    //      |      ^
+    pub fn render(&self, chunk_name: &str, src: &str) -> String {
+        let span = self.span();
+        let loc = span.start;
+        let mut res = format!("{}:{}: {}", chunk_name, loc, self.msg());
+
+        // the lexer takes "\r\n" and a lone '\r' as line breaks as well
+        let src = src.replace("\r\n", "\n").replace('\r', "\n");
+
+        if let Some(line) = src.lines().nth(loc.line - 1) {
+            let gutter = loc.line.to_string();
+            let pad = " ".repeat(gutter.len());
+
+            // keep tabs in the padding so that the caret lines up
+            let indent = line.chars()
+                .take(loc.column - 1)
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+
+            // underline up to the end of the line for multi-line spans
+            let width = if span.end.line == loc.line {
+                span.end.column.saturating_sub(loc.column)
+            } else {
+                line.chars().count().saturating_sub(loc.column - 1)
+            };
+            let marks = "^".repeat(width.max(1));
+
+            res.push_str(&format!("\n{} |\n{} | {}\n{} | {}{}", pad, gutter, line, pad, indent, marks));
+        }
+
+        res
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.loc(), self.msg())
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let err = Error::Parse {
+            span: Span::new(
+                Location { line: 2, column: 6, offset: 11 },
+                Location { line: 2, column: 7, offset: 12 }
+            ),
+            msg: "expected 'then', found '='".to_string()
+        };
+
+        assert_eq!(
+            err.render("script.lua", "a = 1\nif a = 1 then\nend"),
+            "script.lua:2:6: expected 'then', found '='\n  |\n2 | if a = 1 then\n  |      ^"
+        );
+
+        let err = Error::Runtime {
+            span: Span::new(
+                Location { line: 1, column: 5, offset: 4 },
+                Location { line: 1, column: 13, offset: 12 }
+            ),
+            msg: "attempt to perform arithmetic on a nil value".to_string()
+        };
+
+        assert_eq!(
+            err.render("script.lua", "a = b + true"),
+            "script.lua:1:5: attempt to perform arithmetic on a nil value\n  |\n1 | a = b + true\n  |     ^^^^^^^^"
+        );
+    }
+}
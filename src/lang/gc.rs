@@ -0,0 +1,265 @@
+use std::{cell::RefCell, collections::HashMap, rc::{Rc, Weak}};
+
+use super::{vm::{Value, Closure, Upvalue}, table::Table};
+
+// reference counting frees a value once nothing refers to it, but not the
+// values referring to each other, like a table stored in itself or a local
+// function calling itself through its upvalue
+//
+// so every table, function and upvalue is tracked, and once in a while those
+// only kept alive by such cycles are found and emptied, which frees them
+
+// a collection waits for at least this many tracked objects
+const MIN_THRESHOLD: usize = 1024;
+
+pub(super) enum Object {
+    Table(Weak<RefCell<Table>>),
+    Function(Weak<Closure>),
+    Upvalue(Weak<RefCell<Upvalue>>)
+}
+
+// an object still alive, held while collecting
+enum Live {
+    Table(Rc<RefCell<Table>>),
+    Function(Rc<Closure>),
+    Upvalue(Rc<RefCell<Upvalue>>)
+}
+
+struct Heap {
+    objects: Vec<Object>,
+    // the number of objects which makes `step` collect
+    threshold: usize
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const { RefCell::new(Heap { objects: vec![], threshold: MIN_THRESHOLD }) };
+}
+
+pub(super) fn track(obj: Object) {
+    HEAP.with(|heap| heap.borrow_mut().objects.push(obj));
+}
+
+// collects once the objects made since the last collection are as many
+// as those which survived it, must not be called while a table is borrowed
+pub(super) fn step() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.objects.len() >= heap.threshold
+    });
+
+    if due {
+        collect();
+    }
+}
+
+// frees the objects only kept alive by cycles, and gives how many there were
+//
+// the references to an object which don't come from other tracked objects
+// come from outside, like the stack or the globals of a vm, or rust code
+// holding a value, so the objects having some are alive, and so is
+// everything they refer to
+pub fn collect() -> usize {
+    let objects = HEAP.with(|heap| std::mem::take(&mut heap.borrow_mut().objects))
+        .iter()
+        .filter_map(Live::upgrade)
+        .collect::<Vec<_>>();
+
+    let index = objects.iter()
+        .enumerate()
+        .map(|(i, obj)| (obj.addr(), i))
+        .collect::<HashMap<_, _>>();
+
+    // the references from outside, leaving out the one held here
+    let mut refs = objects.iter()
+        .map(|obj| obj.count() - 1)
+        .collect::<Vec<_>>();
+    for obj in &objects {
+        obj.children(|addr| {
+            if let Some(&i) = index.get(&addr) {
+                refs[i] -= 1;
+            }
+        });
+    }
+
+    let mut alive = refs.iter().map(|&n| n > 0).collect::<Vec<_>>();
+    let mut work = (0..objects.len()).filter(|&i| alive[i]).collect::<Vec<_>>();
+    while let Some(i) = work.pop() {
+        objects[i].children(|addr| {
+            if let Some(&j) = index.get(&addr) {
+                if !alive[j] {
+                    alive[j] = true;
+                    work.push(j);
+                }
+            }
+        });
+    }
+
+    // empty the garbage, which frees it once the references here are dropped
+    let mut values = vec![];
+    let mut count = 0;
+    for (obj, _) in objects.iter().zip(&alive).filter(|(_, alive)| !**alive) {
+        obj.clear(&mut values);
+        count += 1;
+    }
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+
+        heap.objects.extend(objects.iter()
+            .zip(&alive)
+            .filter(|(_, alive)| **alive)
+            .map(|(obj, _)| obj.downgrade()));
+        heap.threshold = (heap.objects.len() * 2).max(MIN_THRESHOLD);
+    });
+
+    drop(objects);
+    release(values);
+
+    count
+}
+
+// drops the values, first emptying the tables and functions only they refer to,
+// so that freeing a long chain of them like `a = {a}` never recurses
+pub(super) fn release(mut values: Vec<Value>) {
+    while let Some(val) = values.pop() {
+        match val {
+            Value::Table(t) => {
+                if let Ok(t) = Rc::try_unwrap(t) {
+                    values.extend(t.into_inner().take_values());
+                }
+            },
+            Value::Function(f) => {
+                if let Ok(mut f) = Rc::try_unwrap(f) {
+                    values.extend(f.take_values());
+                }
+            },
+
+            _ => {}
+        }
+    }
+}
+
+// the address of the object a value refers to, if it is tracked
+fn value_addr(val: &Value) -> Option<usize> {
+    match val {
+        Value::Table(t) => Some(Rc::as_ptr(t) as *const () as usize),
+        Value::Function(f) => Some(Rc::as_ptr(f) as *const () as usize),
+
+        _ => None
+    }
+}
+
+impl Live {
+    fn upgrade(obj: &Object) -> Option<Live> {
+        match obj {
+            Object::Table(x) => x.upgrade().map(Live::Table),
+            Object::Function(x) => x.upgrade().map(Live::Function),
+            Object::Upvalue(x) => x.upgrade().map(Live::Upvalue)
+        }
+    }
+
+    fn downgrade(&self) -> Object {
+        match self {
+            Live::Table(x) => Object::Table(Rc::downgrade(x)),
+            Live::Function(x) => Object::Function(Rc::downgrade(x)),
+            Live::Upvalue(x) => Object::Upvalue(Rc::downgrade(x))
+        }
+    }
+
+    fn addr(&self) -> usize {
+        match self {
+            Live::Table(x) => Rc::as_ptr(x) as *const () as usize,
+            Live::Function(x) => Rc::as_ptr(x) as *const () as usize,
+            Live::Upvalue(x) => Rc::as_ptr(x) as *const () as usize
+        }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            Live::Table(x) => Rc::strong_count(x),
+            Live::Function(x) => Rc::strong_count(x),
+            Live::Upvalue(x) => Rc::strong_count(x)
+        }
+    }
+
+    // calls `f` with the address of every object this one refers to,
+    // once for each reference
+    fn children(&self, mut f: impl FnMut(usize)) {
+        match self {
+            Live::Table(x) => {
+                for val in x.borrow().values() {
+                    if let Some(addr) = value_addr(val) {
+                        f(addr);
+                    }
+                }
+            },
+            Live::Function(x) => {
+                for upvalue in x.upvalues() {
+                    f(Rc::as_ptr(upvalue) as *const () as usize);
+                }
+            },
+            Live::Upvalue(x) => {
+                if let Upvalue::Closed(val) = &*x.borrow() {
+                    if let Some(addr) = value_addr(val) {
+                        f(addr);
+                    }
+                }
+            }
+        }
+    }
+
+    // drops the references to other objects, a function
+    // refers to them through its upvalues, which are emptied
+    fn clear(&self, values: &mut Vec<Value>) {
+        match self {
+            Live::Table(x) => values.extend(x.borrow_mut().take_values()),
+            Live::Function(_) => {},
+            Live::Upvalue(x) => {
+                if let Upvalue::Closed(val) = &mut *x.borrow_mut() {
+                    values.push(std::mem::replace(val, Value::Nil));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_rc(val: &Value) -> Rc<RefCell<Table>> {
+        match val {
+            Value::Table(t) => t.clone(),
+            _ => panic!("expected a table")
+        }
+    }
+
+    #[test]
+    fn cycle() {
+        // a table holding itself, and two tables holding each other
+        let a: Value = Table::new().into();
+        table_rc(&a).borrow_mut().set("self".into(), a.clone()).unwrap();
+
+        let b: Value = Table::new().into();
+        let c: Value = Table::new().into();
+        table_rc(&b).borrow_mut().set("c".into(), c.clone()).unwrap();
+        table_rc(&c).borrow_mut().set(Value::Integer(1), b.clone()).unwrap();
+
+        let weak_a = Rc::downgrade(&table_rc(&a));
+        let weak_b = Rc::downgrade(&table_rc(&b));
+
+        drop(a);
+        drop(b);
+        assert!(weak_a.upgrade().is_some());
+
+        // c is still held here, and keeps b alive
+        assert_eq!(collect(), 1);
+        assert!(weak_a.upgrade().is_none());
+        assert!(weak_b.upgrade().is_some());
+        assert!(matches!(table_rc(&c).borrow().get(&Value::Integer(1)), Value::Table(_)));
+
+        drop(c);
+        assert_eq!(collect(), 2);
+        assert!(weak_b.upgrade().is_none());
+    }
+}
@@ -1,43 +1,68 @@
 use std::{iter::Peekable, vec::IntoIter};
 
-use super::token::{Token, TokenKind, Location};
+use super::{token::{Token, TokenKind, TokenValue, Location, Span}, error::{Error, Result}};
 
 pub struct Lexer {
-    text: Peekable<IntoIter<char>>,
+    // the source as given, which may hold invalid utf-8
+    src: Vec<u8>,
+    // the chars of the source with the bytes each one takes,
+    // a run of invalid bytes comes as a single U+FFFD
+    text: Peekable<IntoIter<(char, usize)>>,
     ch: Option<char>,
-    loc: Location
+    width: usize,
+    loc: Location,
+    // where the token being analyzed starts
+    start: Location
 }
 
 impl Lexer {
-    pub fn new(text: &str) -> Lexer {
-        let mut text = text.chars()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .peekable();
-        let ch = text.next();
+    pub fn new(src: impl AsRef<[u8]>) -> Lexer {
+        let src = src.as_ref().to_vec();
+
+        let mut text = vec![];
+        for chunk in src.utf8_chunks() {
+            text.extend(chunk.valid().chars().map(|ch| (ch, ch.len_utf8())));
+            if !chunk.invalid().is_empty() {
+                text.push((char::REPLACEMENT_CHARACTER, chunk.invalid().len()));
+            }
+        }
+
+        let mut text = text.into_iter().peekable();
+        let (ch, width) = match text.next() {
+            Some((ch, width)) => (Some(ch), width),
+            None => (None, 0)
+        };
 
-        Lexer { text, ch, loc: Location::new() }
+        Lexer { src, text, ch, width, loc: Location::new(), start: Location::new() }
     }
 
-    pub fn analyze(&mut self) -> Vec<Token> {
+    pub fn analyze(&mut self) -> Result<Vec<Token>> {
         let mut res = vec![];
 
+        // skip the shebang line of an executable script
+        if self.ch == Some('#') && self.peek() == Some('!') {
+            self.skip_line();
+        }
+
         while let Some(ch) = self.ch {
             if ch.is_whitespace() {
                 self.skip_whitespace();
                 continue;
             }
 
-            if ch.is_digit(10) {
-                let value = self.get_number();
+            self.start = self.loc;
+
+            // a number may also start with a '.', like .5
+            if ch.is_ascii_digit() || (ch == '.' && self.peek().is_some_and(|ch| ch.is_ascii_digit())) {
+                let (kind, value) = self.get_number()?;
                 res.push(self.make_token(
-                    TokenKind::Number,
-                    Some(value)
+                    kind,
+                    Some(TokenValue::Text(value))
                 ));
                 continue;
             }
 
-            if ch.is_alphabetic() {
+            if ch.is_alphabetic() || ch == '_' {
                 let ident = self.get_ident();
 
                 res.push(
@@ -48,25 +73,34 @@ impl Lexer {
                         "then"      => self.make_token( TokenKind::Then, None ),
                         "do"        => self.make_token( TokenKind::Do, None ),
                         "while"     => self.make_token( TokenKind::While, None ),
+                        "repeat"    => self.make_token( TokenKind::Repeat, None ),
+                        "until"     => self.make_token( TokenKind::Until, None ),
+                        "for"       => self.make_token( TokenKind::For, None ),
+                        "in"        => self.make_token( TokenKind::In, None ),
+                        "break"     => self.make_token( TokenKind::Break, None ),
+                        "goto"      => self.make_token( TokenKind::Goto, None ),
                         "end"       => self.make_token( TokenKind::End, None ),
                         "function"  => self.make_token( TokenKind::Function, None ),
+                        "return"    => self.make_token( TokenKind::Return, None ),
+                        "local"     => self.make_token( TokenKind::Local, None ),
                         "true"      => self.make_token( TokenKind::True, None ),
                         "false"     => self.make_token( TokenKind::False, None ),
+                        "nil"       => self.make_token( TokenKind::Nil, None ),
                         "and"       => self.make_token( TokenKind::And, None ),
                         "not"       => self.make_token( TokenKind::Not, None ),
                         "or"        => self.make_token( TokenKind::Or, None ),
 
-                        _ => self.make_token( TokenKind::Ident, Some(ident) )
+                        _ => self.make_token( TokenKind::Ident, Some(TokenValue::Text(ident)) )
                     }
                 );
                 continue;
             }
 
             if ['\'', '"'].contains(&ch) {
-                let value = self.get_qte_string(ch);
+                let value = self.get_qte_string(ch)?;
                 res.push(self.make_token(
                     TokenKind::String,
-                    Some(value)
+                    Some(TokenValue::Bytes(value))
                 ));
                 continue;
             }
@@ -74,22 +108,30 @@ impl Lexer {
             match ch {
                 '[' => {
                     // check if this is the start of a string
-                    if let Some(ch) = self.peek() {
-                        if ['=', '['].contains(&ch) {
-                            self.advance();
+                    if let Some('=' | '[') = self.peek() {
+                        self.advance();
 
-                            let value = self.get_long_string();
-                            res.push(self.make_token(
-                                TokenKind::String,
-                                Some(value)
-                            ));
-                        // otherwise, it's a left square parenthesis
-                        } else {
-                            res.push(self.make_token(
-                                TokenKind::Lsqr,
-                                None
-                            ));
-                        }
+                        let count = match self.get_long_bracket_level() {
+                            Some(count) => count,
+                            None => return Err(self.error("invalid long string delimiter".to_string()))
+                        };
+
+                        let value = match self.get_long_string(count) {
+                            Some(value) => value,
+                            None => return Err(self.error("unfinished long string".to_string()))
+                        };
+
+                        res.push(self.make_token(
+                            TokenKind::String,
+                            Some(TokenValue::Bytes(value))
+                        ));
+                        continue;
+                    // otherwise, it's a left square parenthesis
+                    } else {
+                        res.push(self.make_token(
+                            TokenKind::Lsqr,
+                            None
+                        ));
                     }
                 },
                 ']' => res.push(self.make_token(
@@ -117,10 +159,18 @@ impl Lexer {
                     TokenKind::Plus,
                     None
                 )),
-                '-' => res.push(self.make_token(
-                    TokenKind::Minus,
-                    None
-                )),
+                '-' => {
+                    // - or a comment?
+                    if let Some(_ch @ '-') = self.peek() {
+                        self.skip_comment()?;
+                        continue;
+                    }
+
+                    res.push(self.make_token(
+                        TokenKind::Minus,
+                        None
+                    ));
+                },
                 '*' => res.push(self.make_token(
                     TokenKind::Mul,
                     None
@@ -185,10 +235,22 @@ impl Lexer {
                     TokenKind::Comma,
                     None
                 )),
-                ':' => res.push(self.make_token(
-                    TokenKind::Colon,
-                    None
-                )),
+                ':' => {
+                    // : or :: ?
+                    if let Some(_ch @ ':') = self.peek() {
+                        self.advance();
+
+                        res.push(self.make_token(
+                            TokenKind::DoubleColon,
+                            None
+                        ));
+                    } else {
+                        res.push(self.make_token(
+                            TokenKind::Colon,
+                            None
+                        ));
+                    }
+                },
                 ';' => res.push(self.make_token(
                     TokenKind::Semi,
                     None
@@ -211,13 +273,17 @@ impl Lexer {
                     }
                 },
                 '~' => {
-                    // it must be ~=, so we simply skip the first '~'
-                    self.advance();
+                    // it must be ~=
+                    if let Some(_ch @ '=') = self.peek() {
+                        self.advance();
 
-                    res.push(self.make_token(
-                        TokenKind::UnEq,
-                        None
-                    ));
+                        res.push(self.make_token(
+                            TokenKind::UnEq,
+                            None
+                        ));
+                    } else {
+                        return Err(self.error(format!("unexpected symbol '{}'", ch)));
+                    }
                 }
                 '<' => {
                     // < or <= ?
@@ -252,36 +318,94 @@ impl Lexer {
                     }
                 },
 
-                _ => panic!("Unexpected char.")
+                _ => return Err(self.error(format!("unexpected symbol '{}'", ch)))
             }
 
             self.advance();
+
+            // the token above was made on its last char
+            if let Some(tok) = res.last_mut() {
+                tok.end = self.loc;
+            }
         }
 
+        self.start = self.loc;
         res.push(self.make_token( TokenKind::Eof, None ));
 
-        res
+        Ok(res)
     }
 
     fn advance(&mut self) {
-        self.ch = self.text.next();
+        let (prev, width) = match self.ch {
+            Some(ch) => (ch, self.width),
+            None => return
+        };
+        (self.ch, self.width) = match self.text.next() {
+            Some((ch, width)) => (Some(ch), width),
+            None => (None, 0)
+        };
 
-        if self.ch == Some('\r') || self.ch == Some('\n') {
-            self.loc.new_line();
-        } else {
-            self.loc.advance();
+        match prev {
+            // "\r\n" is a single line break, counted at the '\n'
+            '\r' if self.ch == Some('\n') => self.loc.advance(1),
+            '\r' | '\n' => self.loc.new_line(1),
+
+            _ => self.loc.advance(width)
         }
     }
 
     fn peek(&mut self) -> Option<char> {
-        match self.text.peek() {
-            Some(ch) => Some(*ch),
-            None => None
+        self.text.peek().map(|&(ch, _)| ch)
+    }
+
+    // the bytes of the current char as they are in the source
+    fn raw(&self) -> &[u8] {
+        &self.src[self.loc.offset..self.loc.offset + self.width]
+    }
+
+    fn make_token(&self, kind: TokenKind, value: Option<TokenValue>) -> Token {
+        Token { kind, value, loc: self.start, end: self.loc }
+    }
+
+    // an error on the token being analyzed
+    fn error(&self, msg: String) -> Error {
+        Error::Lex {
+            span: Span::new(self.start, self.loc),
+            msg
         }
     }
 
-    fn make_token(&self, kind: TokenKind, value: Option<String>) -> Token {
-        Token { kind, value, loc: self.loc }
+    fn skip_line(&mut self) {
+        while let Some(ch) = self.ch {
+            if ch == '\r' || ch == '\n' {
+                break;
+            }
+
+            self.advance();
+        }
+    }
+
+    fn skip_comment(&mut self) -> Result<()> {
+        // skip the "--"
+        self.advance();
+        self.advance();
+
+        // a long comment is written like a long string
+        if let Some('[') = self.ch {
+            self.advance();
+
+            if let Some(count) = self.get_long_bracket_level() {
+                return match self.get_long_string(count) {
+                    Some(_) => Ok(()),
+                    None => Err(self.error("unfinished long comment".to_string()))
+                };
+            }
+        }
+
+        // otherwise, it lasts until the end of the line
+        self.skip_line();
+
+        Ok(())
     }
 
     fn skip_whitespace(&mut self) {
@@ -294,41 +418,163 @@ impl Lexer {
         }
     }
 
-    fn get_number(&mut self) -> String {
-        let mut res = String::new();
+    // reads a numeral and gives its kind and its value in a form
+    // that `str::parse` accepts, i64 for integers and f64 for floats
+    fn get_number(&mut self) -> Result<(TokenKind, String)> {
+        let mut text = String::new();
+
+        // like lua, read everything that may be part of a numeral first,
+        // then check whether it's well-formed
+        let mut exp = ['e', 'E'];
+        if self.ch == Some('0') && matches!(self.peek(), Some('x' | 'X')) {
+            exp = ['p', 'P'];
+
+            text.push('0');
+            self.advance();
+            text.push(self.ch.unwrap());
+            self.advance();
+        }
 
         while let Some(ch) = self.ch {
-            if ch.is_digit(10) {
-                res.push(ch);
+            if exp.contains(&ch) {
+                text.push(ch);
+                self.advance();
+
+                // the exponent may be signed
+                if let Some(sign @ ('+' | '-')) = self.ch {
+                    text.push(sign);
+                    self.advance();
+                }
+            } else if ch.is_ascii_hexdigit() || ch == '.' {
+                text.push(ch);
                 self.advance();
             } else {
                 break;
             }
         }
 
-        // handle real number
-        if let Some(ch @ '.') = self.ch {
-            res.push(ch);
-            self.advance();
+        // a numeral touching a letter, like 3x, is malformed as well
+        if let Some(ch) = self.ch {
+            if ch.is_alphabetic() || ch == '_' {
+                text.push(ch);
+                self.advance();
+            }
+        }
 
-            while let Some(ch) = self.ch {
-                if ch.is_digit(10) {
-                    res.push(ch);
-                    self.advance();
-                } else {
-                    break;
-                }
+        let res = if text.starts_with("0x") || text.starts_with("0X") {
+            Self::convert_hex(&text[2..])
+        } else {
+            Self::convert_decimal(&text)
+        };
+
+        match res {
+            Some(res) => Ok(res),
+            None => Err(self.error(format!("malformed number near '{}'", text)))
+        }
+    }
+
+    // digits [ '.' digits ] [ ('e' | 'E') [ '+' | '-' ] digits ]
+    pub(super) fn convert_decimal(text: &str) -> Option<(TokenKind, String)> {
+        let (mantissa, exp) = match text.find(['e', 'E']) {
+            Some(i) => (&text[..i], Some(&text[i + 1..])),
+            None => (text, None)
+        };
+
+        let (int, frac) = match mantissa.split_once('.') {
+            Some((int, frac)) => (int, Some(frac)),
+            None => (mantissa, None)
+        };
+
+        let is_digits = |s: &str| s.chars().all(|ch| ch.is_ascii_digit());
+
+        if !is_digits(int) || !frac.is_none_or(is_digits)
+            || int.len() + frac.map_or(0, |frac| frac.len()) == 0
+        {
+            return None;
+        }
+
+        if let Some(exp) = exp {
+            let digits = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+
+            if digits.is_empty() || !is_digits(digits) {
+                return None;
             }
         }
 
-        res
+        // an integer that doesn't fit in 64 bits becomes a float
+        if frac.is_none() && exp.is_none() {
+            if let Ok(x) = int.parse::<i64>() {
+                return Some((TokenKind::Integer, x.to_string()));
+            }
+        }
+
+        text.parse::<f64>().ok()
+            .map(|x| (TokenKind::Float, x.to_string()))
+    }
+
+    // hexdigits [ '.' hexdigits ] [ ('p' | 'P') [ '+' | '-' ] digits ],
+    // after the leading "0x"
+    pub(super) fn convert_hex(text: &str) -> Option<(TokenKind, String)> {
+        let (mantissa, exp) = match text.find(['p', 'P']) {
+            Some(i) => (&text[..i], Some(&text[i + 1..])),
+            None => (text, None)
+        };
+
+        let (int, frac) = match mantissa.split_once('.') {
+            Some((int, frac)) => (int, Some(frac)),
+            None => (mantissa, None)
+        };
+
+        let is_hex_digits = |s: &str| s.chars().all(|ch| ch.is_ascii_hexdigit());
+
+        if !is_hex_digits(int) || !frac.is_none_or(is_hex_digits)
+            || int.len() + frac.map_or(0, |frac| frac.len()) == 0
+        {
+            return None;
+        }
+
+        // hex integers wrap around
+        if frac.is_none() && exp.is_none() {
+            let x = int.chars().fold(0u64, |acc, ch| {
+                acc.wrapping_mul(16).wrapping_add(ch.to_digit(16).unwrap() as u64)
+            });
+
+            return Some((TokenKind::Integer, (x as i64).to_string()));
+        }
+
+        // the binary exponent, in powers of 2
+        let mut exp = match exp {
+            Some(exp) => {
+                let digits = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+
+                if digits.is_empty() || !digits.chars().all(|ch| ch.is_ascii_digit()) {
+                    return None;
+                }
+
+                // saturate, the result is 0 or inf anyway
+                let x = digits.parse::<i32>().unwrap_or(i32::MAX / 2);
+                if exp.starts_with('-') { -x } else { x }
+            },
+            None => 0
+        };
+
+        let mut x = 0.0;
+        for ch in int.chars() {
+            x = x * 16.0 + ch.to_digit(16).unwrap() as f64;
+        }
+        for ch in frac.unwrap_or("").chars() {
+            x = x * 16.0 + ch.to_digit(16).unwrap() as f64;
+            exp -= 4;
+        }
+
+        Some((TokenKind::Float, (x * 2f64.powi(exp)).to_string()))
     }
 
     fn get_ident(&mut self) -> String {
         let mut res = String::new();
 
         while let Some(ch) = self.ch {
-            if ch.is_alphabetic() || ch.is_digit(10) || ch == '_' {
+            if ch.is_alphabetic() || ch.is_ascii_digit() || ch == '_' {
                 res.push(ch);
                 self.advance();
             } else {
@@ -339,95 +585,267 @@ impl Lexer {
         res
     }
 
-    fn get_qte_string(&mut self, qte: char) -> String {
+    // gives the bytes of the string, which escapes may make invalid utf-8
+    fn get_qte_string(&mut self, qte: char) -> Result<Vec<u8>> {
         // skip the first quote
         self.advance();
 
-        let mut res = String::new();
+        let mut res = vec![];
 
-        while let Some(ch) = self.ch {
-            if ch != qte {
-                res.push(ch);
-                self.advance();
-            } else {
-                break;
+        loop {
+            match self.ch {
+                Some(ch) if ch == qte => break,
+                Some('\\') => self.get_escape(&mut res)?,
+                Some('\r' | '\n') | None => {
+                    return Err(self.error("unfinished string".to_string()));
+                },
+
+                Some(_) => {
+                    res.extend_from_slice(self.raw());
+                    self.advance();
+                }
             }
         }
 
         // skip the second quote
         self.advance();
 
-        res
+        Ok(res)
     }
 
-    fn get_long_string(&mut self) -> String {
-        // since the analyze method has already skip the first '['
-        // we could get the count of '=' directly
-        let mut count = 0;
+    // reads an escape sequence starting with '\' and appends what it stands for
+    fn get_escape(&mut self, res: &mut Vec<u8>) -> Result<()> {
+        // skip the '\'
+        self.advance();
 
-        while let Some(ch) = self.ch {
-            if ch == '=' {
-                count += 1;
+        let ch = match self.ch {
+            Some(ch) => ch,
+            None => return Err(self.error("unfinished string".to_string()))
+        };
+
+        let byte = match ch {
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'v' => 0x0b,
+            '\\' => b'\\',
+            '"' => b'"',
+            '\'' => b'\'',
+
+            // an escaped line break is a line break,
+            // with "\r\n" taken as a single one
+            '\r' | '\n' => {
                 self.advance();
-            } else {
-                break;
-            }
+
+                if let Some(next @ ('\r' | '\n')) = self.ch {
+                    if next != ch {
+                        self.advance();
+                    }
+                }
+
+                res.push(b'\n');
+                return Ok(());
+            },
+
+            // '\z' skips the following whitespace, line breaks included
+            'z' => {
+                self.advance();
+                self.skip_whitespace();
+
+                return Ok(());
+            },
+
+            // '\xXX' is a byte in exactly two hex digits
+            'x' => {
+                self.advance();
+
+                let mut byte = 0;
+                for _ in 0..2 {
+                    match self.ch.and_then(|ch| ch.to_digit(16)) {
+                        Some(d) => byte = byte * 16 + d as u8,
+                        None => return Err(self.error("hexadecimal digit expected".to_string()))
+                    }
+
+                    self.advance();
+                }
+
+                res.push(byte);
+                return Ok(());
+            },
+
+            // '\u{XXX}' is a code point encoded in utf-8, which like in lua
+            // may be any value below 2^31, surrogates included
+            'u' => {
+                self.advance();
+
+                if self.ch != Some('{') {
+                    return Err(self.error("missing '{' in \\u{xxxx}".to_string()));
+                }
+                self.advance();
+
+                let mut code: u32 = 0;
+                let mut digits = 0;
+                while let Some(d) = self.ch.and_then(|ch| ch.to_digit(16)) {
+                    code = match code.checked_mul(16) {
+                        Some(code) if code <= 0x7FFFFFFF => code + d,
+                        _ => return Err(self.error("UTF-8 value too large".to_string()))
+                    };
+                    digits += 1;
+
+                    self.advance();
+                }
+
+                if digits == 0 {
+                    return Err(self.error("hexadecimal digit expected".to_string()));
+                }
+                if self.ch != Some('}') {
+                    return Err(self.error("missing '}' in \\u{xxxx}".to_string()));
+                }
+                self.advance();
+
+                utf8_encode(code, res);
+                return Ok(());
+            },
+
+            // '\ddd' is a byte in up to three decimal digits
+            '0'..='9' => {
+                let mut byte: u32 = 0;
+                for _ in 0..3 {
+                    match self.ch.and_then(|ch| ch.to_digit(10)) {
+                        Some(d) => byte = byte * 10 + d,
+                        None => break
+                    }
+
+                    self.advance();
+                }
+
+                if byte > 255 {
+                    return Err(self.error("decimal escape too large".to_string()));
+                }
+
+                res.push(byte as u8);
+                return Ok(());
+            },
+
+            _ => return Err(self.error(format!("invalid escape sequence '\\{}'", ch)))
+        };
+
+        res.push(byte);
+        self.advance();
+
+        Ok(())
+    }
+
+    // reads the '='s of an opening long bracket whose first '[' has been skipped,
+    // gives the count of them if the second '[' follows
+    fn get_long_bracket_level(&mut self) -> Option<usize> {
+        let mut count = 0;
+
+        while let Some('=') = self.ch {
+            count += 1;
+            self.advance();
         }
 
+        if let Some('[') = self.ch {
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    // reads a long string up to the closing bracket with `count` '='s,
+    // gives None if the input ends before it
+    fn get_long_string(&mut self, count: usize) -> Option<Vec<u8>> {
         // skip the second '['
         self.advance();
 
-        let mut res = String::new();
+        // a line break right after the opening bracket is not part of the string
+        if let Some('\r') = self.ch {
+            self.advance();
+
+            if let Some('\n') = self.ch {
+                self.advance();
+            }
+        } else if let Some('\n') = self.ch {
+            self.advance();
+        }
+
+        let mut res = vec![];
 
         // to judge whether it's the string's end or not
-        let mut temp_res = String::new();
+        let mut temp_res = vec![];
         let mut temp_count = 0;
 
         while let Some(ch) = self.ch {
             if ch == ']' {
                 // is it the first ']'?
                 if temp_res.is_empty() {
-                    temp_res.push(ch);
-                } else {
+                    temp_res.push(b']');
+                } else if count == temp_count {
                     // then it's the second ']'
-                    // if the count matches, the string is ended, break the loop
-                    if count == temp_count {
-                        break;
-                    } else {
-                        // otherwise, append the temp string to the res
-                        res.push_str(&temp_res);
-                        res.push(ch);
+                    // if the count matches, the string is ended
+                    // skip the second ']'
+                    self.advance();
 
-                        // clear up the temps
-                        temp_res.clear();
-                        temp_count = 0;
-                    }
+                    return Some(res);
+                } else {
+                    // otherwise, append the temp string to the res,
+                    // this ']' may still start the end of the string
+                    res.extend_from_slice(&temp_res);
+
+                    temp_res.clear();
+                    temp_res.push(b']');
+                    temp_count = 0;
                 }
             } else if ch == '=' && !temp_res.is_empty() {
-                temp_res.push(ch);
+                temp_res.push(b'=');
                 temp_count += 1;
             } else {
                 // not between two ']'s
-                if temp_res.is_empty() {
-                    res.push(ch);
-                } else {
-                    // otherwise, it means that it's not a string's end
-                    res.push_str(&temp_res);
-                    res.push(ch);
+                if !temp_res.is_empty() {
+                    // it means that it's not a string's end
+                    res.extend_from_slice(&temp_res);
 
                     temp_res.clear();
                     temp_count = 0;
                 }
+
+                res.extend_from_slice(self.raw());
             }
 
             self.advance();
         }
 
-        // skip the second ']'
-        self.advance();
+        None
+    }
+}
 
-        res
+// encodes a value below 2^31 the way utf-8 did originally, in up to six bytes
+fn utf8_encode(mut x: u32, res: &mut Vec<u8>) {
+    if x < 0x80 {
+        res.push(x as u8);
+        return;
     }
+
+    // the continuation bytes are made from the end
+    let mut bytes = vec![];
+    // the largest value which still fits in the first byte
+    let mut mfb = 0x3f;
+    loop {
+        bytes.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+
+        if x <= mfb {
+            break;
+        }
+    }
+    bytes.push(((!mfb << 1) | x) as u8);
+
+    res.extend(bytes.iter().rev());
 }
 
 #[cfg(test)]
@@ -437,12 +855,143 @@ mod tests {
     #[test]
     fn long_string() {
         let mut lexer = Lexer::new("===[]==]]===]");
+        let count = lexer.get_long_bracket_level().unwrap();
 
         assert_eq!(
-            "]==]".to_string(),
+            Some(b"]==]".to_vec()),
 
-            lexer.get_long_string()
+            lexer.get_long_string(count)
         );
+
+        let mut lexer = Lexer::new("=[\nx]]=]");
+        let count = lexer.get_long_bracket_level().unwrap();
+
+        assert_eq!(
+            Some(b"x]".to_vec()),
+
+            lexer.get_long_string(count)
+        );
+    }
+
+    #[test]
+    fn escape() {
+        let string = |src: &str| {
+            Lexer::new(src).analyze().map(|toks| match toks[0].value.clone() {
+                Some(TokenValue::Bytes(value)) => value,
+                value => panic!("expected a string, got {:?}", value)
+            })
+        };
+
+        assert_eq!(string(r#""a\"b\n\t\\'""#).unwrap(), b"a\"b\n\t\\'");
+        assert_eq!(string(r"'\65\066\0067'").unwrap(), b"AB\x067");
+        assert_eq!(string(r"'\x41\u{4E2D}\u{1F600}'").unwrap(), "A中😀".as_bytes());
+        assert_eq!(string(r"'\xE4\xB8\xAD'").unwrap(), "中".as_bytes());
+        assert_eq!(string("'a\\z  \n  b\\\r\nc'").unwrap(), b"ab\nc");
+        assert_eq!(string("[[\nx]]").unwrap(), b"x");
+
+        // strings are bytes, which need not be utf-8
+        assert_eq!(string(r"'\255\xFF\0'").unwrap(), b"\xFF\xFF\0");
+        assert_eq!(string(r"'\u{D800}\u{110000}'").unwrap(), b"\xED\xA0\x80\xF4\x90\x80\x80");
+        assert_eq!(string(r"'\u{7FFFFFFF}'").unwrap(), b"\xFD\xBF\xBF\xBF\xBF\xBF");
+
+        assert!(string(r"'\q'").is_err());
+        assert!(string(r"'\256'").is_err());
+        assert!(string(r"'\xG0'").is_err());
+        assert!(string(r"'\u{80000000}'").is_err());
+        assert!(string("'abc").is_err());
+        assert!(string("'ab\nc'").is_err());
+    }
+
+    #[test]
+    fn number() {
+        let number = |src: &str| {
+            Lexer::new(src).analyze().map(|toks| (toks[0].kind, toks[0].value.clone().unwrap()))
+        };
+        let int = |x: &str| (TokenKind::Integer, TokenValue::Text(x.to_string()));
+        let float = |x: f64| (TokenKind::Float, TokenValue::Text(x.to_string()));
+
+        assert_eq!(number("3").unwrap(), int("3"));
+        assert_eq!(number("0xFF").unwrap(), int("255"));
+        assert_eq!(number("0xffffffffffffffff").unwrap(), int("-1"));
+        assert_eq!(number("9223372036854775808").unwrap(), float(9223372036854775808.0));
+        assert_eq!(number("3.").unwrap(), float(3.0));
+        assert_eq!(number(".5").unwrap(), float(0.5));
+        assert_eq!(number("1e10").unwrap(), float(1e10));
+        assert_eq!(number("2E-3").unwrap(), float(2e-3));
+        assert_eq!(number("0x1p4").unwrap(), float(16.0));
+        assert_eq!(number("0xA.8p-1").unwrap(), float(5.25));
+        assert_eq!(number("0x.1").unwrap(), float(0.0625));
+
+        assert!(number("3x").is_err());
+        assert!(number("1e").is_err());
+        assert!(number("0x").is_err());
+        assert!(number("1.2.3").is_err());
+        assert!(number("0x1p").is_err());
+    }
+
+    #[test]
+    fn comment() {
+        let toks = Lexer::new("#!/usr/bin/env rua\na = 1 -- one\n--[==[ b = 2\n]] ]==]c = a - 1 --[ not long\n--")
+            .analyze()
+            .unwrap()
+            .into_iter()
+            .map(|tok| tok.kind)
+            .collect::<Vec<_>>();
+
+        assert_eq!(toks, vec![
+            TokenKind::Ident,
+            TokenKind::Assign,
+            TokenKind::Integer,
+            TokenKind::Ident,
+            TokenKind::Assign,
+            TokenKind::Ident,
+            TokenKind::Minus,
+            TokenKind::Integer,
+            TokenKind::Eof
+        ]);
+
+        assert!(matches!(Lexer::new("--[[ a").analyze(), Err(Error::Lex { .. })));
+    }
+
+    #[test]
+    fn unexpected_char() {
+        let mut lexer = Lexer::new("a = 1 $ 2");
+
+        assert!(matches!(lexer.analyze(), Err(Error::Lex { .. })));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        // bytes that aren't utf-8 are kept as they are in strings and skipped in comments
+        let toks = Lexer::new(b"-- \xFF\na = '\xE9t\xC3\xA9' .. [[\xFF\xFE]] --[[\x80]]").analyze().unwrap();
+        assert_eq!(toks[2].value, Some(TokenValue::Bytes(b"\xE9t\xC3\xA9".to_vec())));
+        assert_eq!(toks[4].value, Some(TokenValue::Bytes(b"\xFF\xFE".to_vec())));
+        assert_eq!(toks[5].kind, TokenKind::Eof);
+
+        // the offsets still count bytes
+        assert_eq!(toks[3].loc.offset, 16);
+
+        assert!(matches!(Lexer::new(b"a = \xFF").analyze(), Err(Error::Lex { .. })));
+    }
+
+    #[test]
+    fn location() {
+        let toks = Lexer::new("a =\r\n  1\n\n'x' .. b").analyze().unwrap();
+        let locs = toks.iter()
+            .map(|tok| (tok.loc.line, tok.loc.column))
+            .collect::<Vec<_>>();
+
+        assert_eq!(locs, vec![(1, 1), (1, 3), (2, 3), (4, 1), (4, 5), (4, 8), (4, 9)]);
+    }
+
+    #[test]
+    fn span() {
+        let toks = Lexer::new("éa == \"x\"").analyze().unwrap();
+        let spans = toks.iter()
+            .map(|tok| (tok.loc.offset, tok.end.offset))
+            .collect::<Vec<_>>();
+
+        assert_eq!(spans, vec![(0, 3), (4, 6), (7, 10), (10, 10)]);
     }
 
     #[test]
@@ -458,19 +1007,19 @@ mod tests {
         let res = vec![
             TokenKind::Ident,
             TokenKind::Assign,
-            TokenKind::Number,
+            TokenKind::Integer,
             TokenKind::If,
             TokenKind::Ident,
             TokenKind::Plus,
-            TokenKind::Number,
+            TokenKind::Integer,
             TokenKind::Ge,
-            TokenKind::Number,
+            TokenKind::Float,
             TokenKind::And,
             TokenKind::Ident,
             TokenKind::Pow,
-            TokenKind::Number,
+            TokenKind::Integer,
             TokenKind::Eq,
-            TokenKind::Number,
+            TokenKind::Integer,
             TokenKind::Then,
             TokenKind::Ident,
             TokenKind::Lpar,
@@ -481,8 +1030,9 @@ mod tests {
         ];
 
         lexer.analyze()
+            .unwrap()
             .into_iter()
-            .zip(res.into_iter())
+            .zip(res)
             .for_each(|(l, r)| {
                 assert_eq!(l.kind, r);
             });
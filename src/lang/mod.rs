@@ -1,3 +1,5 @@
+pub mod error;
+
 pub mod token;
 pub mod lexer;
 
@@ -7,4 +9,9 @@ pub mod parser;
 pub mod compiler;
 pub mod bytecode;
 
+pub mod number;
+pub mod builtin;
+pub mod table;
+pub mod gc;
+
 pub mod vm;
\ No newline at end of file
@@ -0,0 +1,311 @@
+use std::fmt;
+
+use super::{lexer::Lexer, token::TokenKind};
+
+// lua numbers come in two subtypes, 64-bit integers and floats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Float(f64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IntDiv,
+    Mod,
+    Pow,
+    Neg
+}
+
+// 2^63, the first float out of the range of i64
+const I64_END: f64 = 9223372036854775808.0;
+
+impl Number {
+    // converts a string the way lua does for arithmetic, which takes
+    // a numeral with an optional sign and spaces around it
+    pub fn parse(s: &str) -> Option<Number> {
+        let s = s.trim_matches([' ', '\t', '\n', '\r', '\x0b', '\x0c']);
+        let (neg, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s.strip_prefix('+').unwrap_or(s))
+        };
+
+        let (kind, text) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => Lexer::convert_hex(hex)?,
+            None => Lexer::convert_decimal(s)?
+        };
+
+        let x = match kind {
+            TokenKind::Integer => Number::Integer(text.parse().ok()?),
+            _ => Number::Float(text.parse().ok()?)
+        };
+
+        if neg {
+            Number::arith(ArithOp::Neg, x, x)
+        } else {
+            Some(x)
+        }
+    }
+
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Integer(x) => x as f64,
+            Number::Float(x) => x
+        }
+    }
+
+    // performs the operation following lua's rules: integers stay integers
+    // except for '/' and '^', and a float operand makes the result a float,
+    // the right operand is ignored for unary operations
+    //
+    // gives None for an integer division or modulo by zero
+    pub fn arith(op: ArithOp, a: Number, b: Number) -> Option<Number> {
+        use Number::*;
+
+        match (op, a, b) {
+            (ArithOp::Add, Integer(a), Integer(b)) => Some(Integer(a.wrapping_add(b))),
+            (ArithOp::Sub, Integer(a), Integer(b)) => Some(Integer(a.wrapping_sub(b))),
+            (ArithOp::Mul, Integer(a), Integer(b)) => Some(Integer(a.wrapping_mul(b))),
+            (ArithOp::IntDiv, Integer(a), Integer(b)) => {
+                if b == 0 {
+                    return None;
+                }
+
+                // round towards minus infinity
+                let q = a.wrapping_div(b);
+                if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                    Some(Integer(q - 1))
+                } else {
+                    Some(Integer(q))
+                }
+            },
+            (ArithOp::Mod, Integer(a), Integer(b)) => {
+                if b == 0 {
+                    return None;
+                }
+
+                // the result takes the sign of the divisor
+                let m = a.wrapping_rem(b);
+                if m != 0 && (m ^ b) < 0 {
+                    Some(Integer(m + b))
+                } else {
+                    Some(Integer(m))
+                }
+            },
+            (ArithOp::Neg, Integer(a), _) => Some(Integer(a.wrapping_neg())),
+
+            (ArithOp::Neg, a, _) => Some(Float(-a.to_f64())),
+            (op, a, b) => {
+                let (a, b) = (a.to_f64(), b.to_f64());
+
+                Some(Float(match op {
+                    ArithOp::Add => a + b,
+                    ArithOp::Sub => a - b,
+                    ArithOp::Mul => a * b,
+                    ArithOp::Div => a / b,
+                    ArithOp::IntDiv => (a / b).floor(),
+                    ArithOp::Mod => {
+                        let m = a % b;
+
+                        if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+                            m + b
+                        } else {
+                            m
+                        }
+                    },
+                    ArithOp::Pow => a.powf(b),
+                    ArithOp::Neg => unreachable!()
+                }))
+            }
+        }
+    }
+
+    // integers and floats are compared by their exact values
+    pub fn equal(a: Number, b: Number) -> bool {
+        use Number::*;
+
+        match (a, b) {
+            (Integer(a), Integer(b)) => a == b,
+            (Float(a), Float(b)) => a == b,
+            (Integer(i), Float(f)) | (Float(f), Integer(i)) => {
+                f.fract() == 0.0 && (-I64_END..I64_END).contains(&f) && f as i64 == i
+            }
+        }
+    }
+
+    pub fn less_than(a: Number, b: Number) -> bool {
+        use Number::*;
+
+        match (a, b) {
+            (Integer(a), Integer(b)) => a < b,
+            (Float(a), Float(b)) => a < b,
+
+            // i < f <=> i < ceil(f)
+            (Integer(i), Float(f)) => {
+                if f.is_nan() {
+                    false
+                } else if f >= I64_END {
+                    true
+                } else if f > -I64_END {
+                    i < f.ceil() as i64
+                } else {
+                    false
+                }
+            },
+            // f < i <=> floor(f) < i
+            (Float(f), Integer(i)) => {
+                if f.is_nan() || f >= I64_END {
+                    false
+                } else if f >= -I64_END {
+                    (f.floor() as i64) < i
+                } else {
+                    true
+                }
+            }
+        }
+    }
+
+    pub fn less_equal(a: Number, b: Number) -> bool {
+        use Number::*;
+
+        match (a, b) {
+            (Integer(a), Integer(b)) => a <= b,
+            (Float(a), Float(b)) => a <= b,
+
+            // i <= f <=> i <= floor(f)
+            (Integer(i), Float(f)) => {
+                if f.is_nan() {
+                    false
+                } else if f >= I64_END {
+                    true
+                } else if f >= -I64_END {
+                    i <= f.floor() as i64
+                } else {
+                    false
+                }
+            },
+            // f <= i <=> ceil(f) <= i
+            (Float(f), Integer(i)) => {
+                if f.is_nan() || f >= I64_END {
+                    false
+                } else if f > -I64_END {
+                    f.ceil() as i64 <= i
+                } else {
+                    true
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Integer(x) => write!(f, "{}", x),
+            Number::Float(x) => write!(f, "{}", fmt_float(*x))
+        }
+    }
+}
+
+// formats a float the way lua does, which is C's "%.14g"
+// with a trailing ".0" for floats holding an integral value
+fn fmt_float(x: f64) -> String {
+    if x.is_nan() {
+        return if x.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if x.is_infinite() {
+        return if x < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+
+    // the exponent after rounding to 14 significant digits
+    let sci = format!("{:.13e}", x);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+
+    if !(-4..14).contains(&exp) {
+        let mantissa = trim_zeros(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        let res = format!("{:.*}", (13 - exp) as usize, x);
+        let res = trim_zeros(&res);
+
+        if res.contains('.') {
+            res.to_string()
+        } else {
+            format!("{}.0", res)
+        }
+    }
+}
+
+fn trim_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Number::*;
+
+    #[test]
+    fn arith() {
+        assert_eq!(Number::arith(ArithOp::Add, Integer(i64::MAX), Integer(1)), Some(Integer(i64::MIN)));
+        assert_eq!(Number::arith(ArithOp::Add, Integer(1), Float(2.0)), Some(Float(3.0)));
+        assert_eq!(Number::arith(ArithOp::Div, Integer(6), Integer(3)), Some(Float(2.0)));
+        assert_eq!(Number::arith(ArithOp::IntDiv, Integer(7), Integer(-2)), Some(Integer(-4)));
+        assert_eq!(Number::arith(ArithOp::IntDiv, Float(7.0), Integer(2)), Some(Float(3.0)));
+        assert_eq!(Number::arith(ArithOp::Mod, Integer(-7), Integer(3)), Some(Integer(2)));
+        assert_eq!(Number::arith(ArithOp::Mod, Float(7.5), Integer(-2)), Some(Float(-0.5)));
+        assert_eq!(Number::arith(ArithOp::Mod, Integer(i64::MIN), Integer(-1)), Some(Integer(0)));
+        assert_eq!(Number::arith(ArithOp::Pow, Integer(2), Integer(10)), Some(Float(1024.0)));
+        assert_eq!(Number::arith(ArithOp::Neg, Integer(i64::MIN), Integer(0)), Some(Integer(i64::MIN)));
+
+        assert_eq!(Number::arith(ArithOp::IntDiv, Integer(1), Integer(0)), None);
+        assert_eq!(Number::arith(ArithOp::Mod, Integer(1), Integer(0)), None);
+    }
+
+    #[test]
+    fn compare() {
+        assert!(Number::equal(Integer(1), Float(1.0)));
+        assert!(!Number::equal(Integer(i64::MAX), Float(I64_END)));
+        assert!(Number::less_than(Integer(i64::MAX), Float(I64_END)));
+        assert!(Number::less_than(Float(1.5), Integer(2)));
+        assert!(Number::less_equal(Integer(2), Float(2.0)));
+        assert!(!Number::less_than(Integer(1), Float(f64::NAN)));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Number::parse("10"), Some(Integer(10)));
+        assert_eq!(Number::parse(" -0x10\n"), Some(Integer(-16)));
+        assert_eq!(Number::parse("+1.5e1"), Some(Float(15.0)));
+        assert_eq!(Number::parse(".5"), Some(Float(0.5)));
+        assert_eq!(Number::parse("9223372036854775808"), Some(Float(9223372036854775808.0)));
+
+        assert_eq!(Number::parse(""), None);
+        assert_eq!(Number::parse("- 1"), None);
+        assert_eq!(Number::parse("1e"), None);
+        assert_eq!(Number::parse("0x"), None);
+        assert_eq!(Number::parse("inf"), None);
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(Integer(3).to_string(), "3");
+        assert_eq!(Float(3.0).to_string(), "3.0");
+        assert_eq!(Float(0.1).to_string(), "0.1");
+        assert_eq!(Float(1.0 / 3.0).to_string(), "0.33333333333333");
+        assert_eq!(Float(1e15).to_string(), "1e+15");
+        assert_eq!(Float(2.5e-5).to_string(), "2.5e-05");
+        assert_eq!(Float(-1.0 / 0.0).to_string(), "-inf");
+    }
+}
@@ -1,10 +1,18 @@
 use std::{vec::IntoIter, iter::Peekable};
 
-use super::{token::{Token, TokenKind}, ast::{StmtList, Stmt, ExprList, IdentList, Ident, Expr, FuncCall}};
+use super::{token::{Token, TokenKind, Location, Span}, ast::{StmtList, Stmt, StmtKind, ExprList, IdentList, Ident, Expr, ExprKind, FuncCall, FuncName, Field}, error::{Error, Result}};
+
+// lua gives up past this many nested statements and expressions,
+// so that a deep input cannot overflow the stack of the parser or the compiler
+const MAX_LEVELS: usize = 200;
 
 pub struct Parser {
     toks: Peekable<IntoIter<Token>>,
-    tok: Token
+    tok: Token,
+    // where the last eaten token ends
+    prev_end: Location,
+    // how deep the syntax tree being parsed is
+    level: usize
 }
 
 impl Parser {
@@ -12,17 +20,35 @@ impl Parser {
         let mut toks = toks.into_iter().peekable();
         let tok = toks.next().unwrap();
 
-        Parser { toks, tok }
+        Parser { toks, tok, prev_end: Location::new(), level: 0 }
     }
 
-    pub fn parse(&mut self) -> StmtList {
-        let res = self.stmt_list();
+    pub fn parse(&mut self) -> Result<StmtList> {
+        let res = self.stmt_list()?;
 
         if !self.matches(TokenKind::Eof) {
-            panic!("Unexpected ended. cur_tok: {:?}", self.tok);
+            return Err(self.error(format!("'<eof>' expected near {}", self.tok)));
         }
 
-        res
+        Ok(res)
+    }
+
+    // parses the whole input as a single expression list, used by the repl
+    // to tell bare expressions apart from statements
+    pub fn parse_expr_list(&mut self) -> Result<ExprList> {
+        let res = self.expr_list()?;
+
+        if !self.matches(TokenKind::Eof) {
+            return Err(self.error(format!("'<eof>' expected near {}", self.tok)));
+        }
+
+        Ok(res)
+    }
+
+    // whether the parser has run into the end of the input,
+    // a failed parse in that state means the input is incomplete
+    pub fn at_eof(&self) -> bool {
+        self.tok.kind == TokenKind::Eof
     }
 
     #[inline]
@@ -30,189 +56,436 @@ impl Parser {
         self.tok.kind == tok_kind
     }
 
-    fn eat(&mut self, tok_kind: TokenKind) {
+    fn eat(&mut self, tok_kind: TokenKind) -> Result<()> {
         if self.matches(tok_kind) {
-            self.tok = self.toks.next().unwrap();
+            self.prev_end = self.tok.end;
+
+            // the eof token is the last one, so stay on it
+            if let Some(tok) = self.toks.next() {
+                self.tok = tok;
+            }
+
+            Ok(())
         } else {
-            panic!("From EAT: expected {:?}, found {:?}.", tok_kind, self.tok);
+            Err(self.error(format!("expected {}, found {}", tok_kind, self.tok)))
         }
     }
 
     fn peek(&mut self) -> TokenKind {
         match self.toks.peek() {
             Some(tok) => tok.kind,
-            None => panic!("Unexpected ended!!!! from peek.")
+            None => TokenKind::Eof
         }
     }
 
-    // stmt_list = { stmt }
-    fn stmt_list(&mut self) -> StmtList {
-        let mut res = vec![];
+    fn error(&self, msg: String) -> Error {
+        Error::Parse { span: self.tok.span(), msg }
+    }
+
+    // goes one level deeper in the syntax tree, to be undone by `leave_level`
+    fn enter_level(&mut self) -> Result<()> {
+        self.level += 1;
+
+        if self.level > MAX_LEVELS {
+            return Err(self.error(format!("too many C levels (limit is {}) near {}", MAX_LEVELS, self.tok)));
+        }
+
+        Ok(())
+    }
+
+    fn leave_level(&mut self) {
+        self.level -= 1;
+    }
+
+    // the span from `start` to the end of the last eaten token
+    fn span_from(&self, start: Location) -> Span {
+        Span::new(start, self.prev_end)
+    }
 
-        while ![TokenKind::Eof, TokenKind::Elseif,
-            TokenKind::Else, TokenKind::End]
+    // whether the token closes a block
+    fn block_follow(&self) -> bool {
+        [TokenKind::Eof, TokenKind::Elseif,
+            TokenKind::Else, TokenKind::End, TokenKind::Until]
             .contains(&self.tok.kind)
-        {
-            res.push(self.stmt());
+    }
+
+    // stmt_list = { stmt | ';' } [ return_stmt ]
+    fn stmt_list(&mut self) -> Result<StmtList> {
+        let mut res = vec![];
+
+        while !self.block_follow() {
+            // an empty statement
+            if self.matches(TokenKind::Semi) {
+                self.eat(TokenKind::Semi)?;
+                continue;
+            }
+
+            // 'return' must be the last statement of a block
+            if self.matches(TokenKind::Return) {
+                res.push(self.return_stmt()?);
+                break;
+            }
+
+            res.push(self.stmt()?);
         }
 
-        res
+        Ok(res)
     }
 
-    // stmt = assign_stmt | if_stmt | while_stmt | func_decl_stmt
-    fn stmt(&mut self) -> Stmt {
-        match self.tok.kind {
-            TokenKind::If => self.if_stmt(),
-            TokenKind::Ident => self.assign_stmt(),
-            TokenKind::While => self.while_stmt(),
-            TokenKind::Function => self.func_decl_stmt(),
+    // return_stmt = 'return' [ expr_list ] [ ';' ]
+    fn return_stmt(&mut self) -> Result<Stmt> {
+        let start = self.tok.loc;
+        self.eat(TokenKind::Return)?;
+
+        let mut expr_list = vec![];
+        if !self.block_follow() && !self.matches(TokenKind::Semi) {
+            expr_list = self.expr_list()?;
+        }
+
+        if self.matches(TokenKind::Semi) {
+            self.eat(TokenKind::Semi)?;
+        }
+
+        Ok(Stmt { kind: StmtKind::Return(expr_list), span: self.span_from(start) })
+    }
+
+    // stmt = if_stmt | while_stmt | repeat_stmt | for_stmt | do_stmt | func_decl_stmt
+    //      | local_stmt | 'break' | goto_stmt | label_stmt | expr_stmt
+    fn stmt(&mut self) -> Result<Stmt> {
+        let start = self.tok.loc;
+        self.enter_level()?;
+
+        let kind = match self.tok.kind {
+            TokenKind::If => self.if_stmt()?,
+            TokenKind::Ident | TokenKind::Lpar => self.expr_stmt()?,
+            TokenKind::While => self.while_stmt()?,
+            TokenKind::Repeat => self.repeat_stmt()?,
+            TokenKind::For => self.for_stmt()?,
+            TokenKind::Do => self.do_stmt()?,
+            TokenKind::Function => self.func_decl_stmt()?,
+            TokenKind::Local => self.local_stmt()?,
+            TokenKind::Break => {
+                self.eat(TokenKind::Break)?;
+
+                StmtKind::Break
+            },
+            TokenKind::Goto => self.goto_stmt()?,
+            TokenKind::DoubleColon => self.label_stmt()?,
+
+            _ => return Err(self.error(format!("unexpected symbol near {}", self.tok)))
+        };
+
+        self.leave_level();
+        Ok(Stmt { kind, span: self.span_from(start) })
+    }
 
-            _ => panic!("Unknown statement. cur_tok: {:?}", self.tok.kind)
+    // local_stmt = 'local' 'function' ident func_body
+    //            | 'local' ident_list [ '=' expr_list ]
+    fn local_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::Local)?;
+
+        if self.matches(TokenKind::Function) {
+            self.eat(TokenKind::Function)?;
+            let ident = self.ident()?;
+            let (args, is_vararg, body) = self.func_body(false)?;
+
+            return Ok(StmtKind::LocalFuncDecl { ident, args, is_vararg, body });
+        }
+
+        let ident_list = self.ident_list()?;
+
+        let mut expr_list = vec![];
+        if self.matches(TokenKind::Assign) {
+            self.eat(TokenKind::Assign)?;
+            expr_list = self.expr_list()?;
         }
+
+        Ok(StmtKind::Local { ident_list, expr_list })
     }
 
-    // func_decl_stmt = 'function' ident '(' ident_list ')' stmt_list 'end'
-    fn func_decl_stmt(&mut self) -> Stmt {
-        self.eat(TokenKind::Function);
-        let ident = self.ident();
-        self.eat(TokenKind::Lpar);
+    // func_decl_stmt = 'function' func_name func_body
+    fn func_decl_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::Function)?;
+        let name = self.func_name()?;
+        let (args, is_vararg, body) = self.func_body(name.method.is_some())?;
+
+        Ok(StmtKind::FuncDecl { name, args, is_vararg, body })
+    }
+
+    // func_name = ident { '.' ident } [ ':' ident ]
+    fn func_name(&mut self) -> Result<FuncName> {
+        let ident = self.ident()?;
+
+        let mut fields = vec![];
+        while self.matches(TokenKind::Dot) {
+            self.eat(TokenKind::Dot)?;
+            fields.push(self.ident()?);
+        }
+
+        let mut method = None;
+        if self.matches(TokenKind::Colon) {
+            self.eat(TokenKind::Colon)?;
+            method = Some(self.ident()?);
+        }
+
+        Ok(FuncName { ident, fields, method })
+    }
+
+    // func_body = '(' [ ident_list [ ',' '...' ] | '...' ] ')' stmt_list 'end'
+    //
+    // a method gets an implicit first parameter 'self'
+    fn func_body(&mut self, is_method: bool) -> Result<(IdentList, bool, StmtList)> {
+        let self_span = self.tok.span();
+        self.eat(TokenKind::Lpar)?;
 
         let mut ident_list = vec![];
-        if !self.matches(TokenKind::Rpar) {
-            ident_list = self.ident_list();
+        if is_method {
+            ident_list.push(Ident { name: "self".to_string(), span: self_span });
         }
-        
-        self.eat(TokenKind::Rpar);
 
-        let stmt_list = self.stmt_list();
+        let mut is_vararg = false;
+        if !self.matches(TokenKind::Rpar) {
+            loop {
+                if self.matches(TokenKind::Arg) {
+                    self.eat(TokenKind::Arg)?;
+                    is_vararg = true;
+                    break;
+                }
 
-        self.eat(TokenKind::End);
+                ident_list.push(self.ident()?);
 
-        Stmt::FuncDecl {
-            ident,
-            args: ident_list,
-            body: stmt_list
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+                self.eat(TokenKind::Comma)?;
+            }
         }
+
+        self.eat(TokenKind::Rpar)?;
+
+        let stmt_list = self.stmt_list()?;
+
+        self.eat(TokenKind::End)?;
+
+        Ok((ident_list, is_vararg, stmt_list))
     }
 
-    // assign_stmt = ident_list '=' expr_list
-    fn assign_stmt(&mut self) -> Stmt {
-        let ident_list = self.ident_list();
-        self.eat(TokenKind::Assign);
-        let expr_list = self.expr_list();
+    // expr_stmt = function_call | target { ',' target } '=' expr_list
+    fn expr_stmt(&mut self) -> Result<StmtKind> {
+        let mut expr = self.suffixed_expr()?;
+
+        if !self.matches(TokenKind::Assign) && !self.matches(TokenKind::Comma) {
+            return match std::mem::replace(&mut expr.kind, ExprKind::Nil) {
+                ExprKind::FuncCall(call) => Ok(StmtKind::FuncCall(call)),
+
+                _ => Err(self.error(format!("syntax error near {}", self.tok)))
+            };
+        }
+
+        let mut targets = vec![*expr];
+        loop {
+            // only variables can be assigned to
+            if !matches!(targets.last().unwrap().kind, ExprKind::Ident(_) | ExprKind::Index { .. }) {
+                return Err(self.error(format!("syntax error near {}", self.tok)));
+            }
+
+            if !self.matches(TokenKind::Comma) {
+                break;
+            }
+
+            self.eat(TokenKind::Comma)?;
+            targets.push(*self.suffixed_expr()?);
+        }
+
+        self.eat(TokenKind::Assign)?;
+        let expr_list = self.expr_list()?;
 
-        Stmt::Assign { ident_list, expr_list }
+        Ok(StmtKind::Assign { targets, expr_list })
     }
 
     // if_stmt = 'if' expr 'then' stmt_list { 'elseif' expr 'then' stmt_list } [ 'else' stmt_list ] 'end'
-    fn if_stmt(&mut self) -> Stmt {
-        self.eat(TokenKind::If);
-        let cond = *self.expr();
-        self.eat(TokenKind::Then);
-        let if_body = self.stmt_list();
+    fn if_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::If)?;
+        let cond = *self.expr()?;
+        self.eat(TokenKind::Then)?;
+        let if_body = self.stmt_list()?;
 
         let mut elseif_conds = vec![];
         let mut elseif_bodies = vec![];
 
         while self.matches(TokenKind::Elseif) {
-            self.eat(TokenKind::Elseif);
-            elseif_conds.push(self.expr());
-            self.eat(TokenKind::Then);
-            elseif_bodies.push(self.stmt_list());
+            self.eat(TokenKind::Elseif)?;
+            elseif_conds.push(self.expr()?);
+            self.eat(TokenKind::Then)?;
+            elseif_bodies.push(self.stmt_list()?);
         }
 
         let mut else_body = vec![];
         if self.matches(TokenKind::Else) {
-            self.eat(TokenKind::Else);
-            else_body = self.stmt_list();
+            self.eat(TokenKind::Else)?;
+            else_body = self.stmt_list()?;
         }
 
-        self.eat(TokenKind::End);
+        self.eat(TokenKind::End)?;
 
-        Stmt::If { cond, if_body, elseif_conds, elseif_bodies, else_body }
+        Ok(StmtKind::If { cond, if_body, elseif_conds, elseif_bodies, else_body })
     }
 
     // while_stmt = 'while' expr 'do' stmt_list 'end'
-    fn while_stmt(&mut self) -> Stmt {
-        self.eat(TokenKind::While);
-        let cond = *self.expr();
-        self.eat(TokenKind::Do);
-        let body = self.stmt_list();
-        self.eat(TokenKind::End);
+    fn while_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::While)?;
+        let cond = *self.expr()?;
+        self.eat(TokenKind::Do)?;
+        let body = self.stmt_list()?;
+        self.eat(TokenKind::End)?;
+
+        Ok(StmtKind::While { cond, body })
+    }
+
+    // repeat_stmt = 'repeat' stmt_list 'until' expr
+    fn repeat_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::Repeat)?;
+        let body = self.stmt_list()?;
+        self.eat(TokenKind::Until)?;
+        let cond = *self.expr()?;
+
+        Ok(StmtKind::Repeat { body, cond })
+    }
+
+    // do_stmt = 'do' stmt_list 'end'
+    fn do_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::Do)?;
+        let body = self.stmt_list()?;
+        self.eat(TokenKind::End)?;
+
+        Ok(StmtKind::Do(body))
+    }
+
+    // goto_stmt = 'goto' ident
+    fn goto_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::Goto)?;
+
+        Ok(StmtKind::Goto(self.ident()?))
+    }
 
-        Stmt::While { cond, body }
+    // label_stmt = '::' ident '::'
+    fn label_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::DoubleColon)?;
+        let ident = self.ident()?;
+        self.eat(TokenKind::DoubleColon)?;
+
+        Ok(StmtKind::Label(ident))
+    }
+
+    // for_stmt = 'for' ident '=' expr ',' expr [ ',' expr ] 'do' stmt_list 'end'
+    //          | 'for' ident_list 'in' expr_list 'do' stmt_list 'end'
+    fn for_stmt(&mut self) -> Result<StmtKind> {
+        self.eat(TokenKind::For)?;
+        let var = self.ident()?;
+
+        if !self.matches(TokenKind::Assign) {
+            let mut vars = vec![var];
+            while self.matches(TokenKind::Comma) {
+                self.eat(TokenKind::Comma)?;
+                vars.push(self.ident()?);
+            }
+
+            self.eat(TokenKind::In)?;
+            let exprs = self.expr_list()?;
+
+            self.eat(TokenKind::Do)?;
+            let body = self.stmt_list()?;
+            self.eat(TokenKind::End)?;
+
+            return Ok(StmtKind::GenericFor { vars, exprs, body });
+        }
+
+        self.eat(TokenKind::Assign)?;
+        let start = self.expr()?;
+        self.eat(TokenKind::Comma)?;
+        let limit = self.expr()?;
+
+        let mut step = None;
+        if self.matches(TokenKind::Comma) {
+            self.eat(TokenKind::Comma)?;
+            step = Some(self.expr()?);
+        }
+
+        self.eat(TokenKind::Do)?;
+        let body = self.stmt_list()?;
+        self.eat(TokenKind::End)?;
+
+        Ok(StmtKind::NumericFor { var, start, limit, step, body })
     }
 
     // ident_list = ident { , ident }
-    fn ident_list(&mut self) -> IdentList {
-        let mut res = vec![self.ident()];
+    fn ident_list(&mut self) -> Result<IdentList> {
+        let mut res = vec![self.ident()?];
 
         while self.matches(TokenKind::Comma) {
-            self.eat(TokenKind::Comma);
-            res.push(self.ident());
+            self.eat(TokenKind::Comma)?;
+            res.push(self.ident()?);
         }
 
-        res
+        Ok(res)
     }
 
     // ident = Ident
-    fn ident(&mut self) -> Ident {
-        let val = self.tok.value.clone();
+    fn ident(&mut self) -> Result<Ident> {
+        let val = self.tok.text().map(str::to_string);
+        let span = self.tok.span();
 
-        self.eat(TokenKind::Ident);
+        self.eat(TokenKind::Ident)?;
 
-        Ident { name: val.unwrap() }
+        Ok(Ident { name: val.unwrap(), span })
     }
 
     // expr_list = expr { , expr }
-    fn expr_list(&mut self) -> ExprList {
-        let mut res = vec![*self.expr()];
+    fn expr_list(&mut self) -> Result<ExprList> {
+        let mut res = vec![*self.expr()?];
 
         while self.matches(TokenKind::Comma) {
-            self.eat(TokenKind::Comma);
-            res.push(*self.expr());
+            self.eat(TokenKind::Comma)?;
+            res.push(*self.expr()?);
         }
 
-        res
+        Ok(res)
     }
 
     // expr = expr_6 { 'or' expr_6 }
-    fn expr(&mut self) -> Box<Expr> {
-        let mut node = self.expr_6();
+    //
+    // the operands of a chain like `a + b + c` are parsed in a loop, which
+    // the compiler follows with a loop too, so they add no level
+    fn expr(&mut self) -> Result<Box<Expr>> {
+        self.enter_level()?;
+        let mut node = self.expr_6()?;
 
         while self.matches(TokenKind::Or) {
-            self.eat(TokenKind::Or);
-            
-            node = Box::new(Expr::BinOp {
-                op: TokenKind::Or,
-                left: node,
-                right: self.expr_6()
-            });
+            self.eat(TokenKind::Or)?;
+
+            node = Self::bin_op(TokenKind::Or, node, self.expr_6()?);
         }
 
-        node
+        self.leave_level();
+        Ok(node)
     }
 
     // expr_6 = expr_5 { 'and' expr_5 }
-    fn expr_6(&mut self) -> Box<Expr> {
-        let mut node = self.expr_5();
+    fn expr_6(&mut self) -> Result<Box<Expr>> {
+        let mut node = self.expr_5()?;
 
         while self.matches(TokenKind::And) {
-            self.eat(TokenKind::And);
-            
-            node = Box::new(Expr::BinOp {
-                op: TokenKind::And,
-                left: node,
-                right: self.expr_5()
-            });
+            self.eat(TokenKind::And)?;
+
+            node = Self::bin_op(TokenKind::And, node, self.expr_5()?);
         }
 
-        node
+        Ok(node)
     }
 
     // expr_5 = expr_4 { ('<' | '>' | '<=' | '>=' | '~=' | '==') expr_4 }
-    fn expr_5(&mut self) -> Box<Expr> {
-        let mut node = self.expr_4();
+    fn expr_5(&mut self) -> Result<Box<Expr>> {
+        let mut node = self.expr_4()?;
 
         while [TokenKind::Lt, TokenKind::Gt,
             TokenKind::Le, TokenKind::Ge,
@@ -220,147 +493,290 @@ impl Parser {
             .contains(&self.tok.kind)
         {
             let temp = self.tok.kind;
-            self.eat(self.tok.kind);
+            self.eat(self.tok.kind)?;
 
-            node = Box::new(Expr::BinOp { op: temp, left: node, right: self.expr_4() });
+            node = Self::bin_op(temp, node, self.expr_4()?);
         }
 
-        node
+        Ok(node)
     }
 
     // expr_4 = expr_3 { '..' expr_3 }
-    fn expr_4(&mut self) -> Box<Expr> {
-        let node = self.expr_3();
+    fn expr_4(&mut self) -> Result<Box<Expr>> {
+        let node = self.expr_3()?;
 
         if self.matches(TokenKind::Concat) {
-            self.eat(TokenKind::Concat);
+            self.eat(TokenKind::Concat)?;
+
+            self.enter_level()?;
+            let right = self.expr_4()?;
+            self.leave_level();
 
-            Box::new(Expr::BinOp {
-                op: TokenKind::Concat,
-                left: node,
-                right: self.expr_4()
-            })
+            Ok(Self::bin_op(TokenKind::Concat, node, right))
         } else {
-            node
+            Ok(node)
         }
     }
 
     // expr_3 = expr_2 { ('+' | '-') expr_2 }
-    fn expr_3(&mut self) -> Box<Expr> {
-        let mut node = self.expr_2();
+    fn expr_3(&mut self) -> Result<Box<Expr>> {
+        let mut node = self.expr_2()?;
 
         while [TokenKind::Plus, TokenKind::Minus]
             .contains(&self.tok.kind)
         {
             let temp = self.tok.kind;
-            self.eat(self.tok.kind);
+            self.eat(self.tok.kind)?;
 
-            node = Box::new(Expr::BinOp { op: temp, left: node, right: self.expr_2() });
+            node = Self::bin_op(temp, node, self.expr_2()?);
         }
 
-        node
+        Ok(node)
     }
 
     // expr_2 = expr_1 { ('*' | '/' | '//' | '%') expr_1 }
-    fn expr_2(&mut self) -> Box<Expr> {
-        let mut node = self.expr_1();
+    fn expr_2(&mut self) -> Result<Box<Expr>> {
+        let mut node = self.expr_1()?;
 
         while [TokenKind::Mul, TokenKind::RealDiv,
             TokenKind::IntDiv, TokenKind::Mod]
             .contains(&self.tok.kind)
         {
             let temp = self.tok.kind;
-            self.eat(self.tok.kind);
+            self.eat(self.tok.kind)?;
 
-            node = Box::new(Expr::BinOp { op: temp, left: node, right: self.expr_1() });
+            node = Self::bin_op(temp, node, self.expr_1()?);
         }
 
-        node
+        Ok(node)
     }
 
     // expr_1 = { ('not' | '#' | '-') } expr_0
-    fn expr_1(&mut self) -> Box<Expr> {
+    fn expr_1(&mut self) -> Result<Box<Expr>> {
         if [TokenKind::Not, TokenKind::Len, TokenKind::Minus]
             .contains(&self.tok.kind)
         {
+            let start = self.tok.loc;
             let temp = self.tok.kind;
-            self.eat(self.tok.kind);
+            self.eat(self.tok.kind)?;
 
-            Box::new(Expr::UnaryOp { op: temp, node: self.expr_1() })
+            self.enter_level()?;
+            let node = self.expr_1()?;
+            self.leave_level();
+
+            Ok(Box::new(Expr {
+                kind: ExprKind::UnaryOp { op: temp, node },
+                span: self.span_from(start)
+            }))
         } else {
             self.expr_0()
         }
     }
 
-    // expr_0 = factor { '^' factor }
-    fn expr_0(&mut self) -> Box<Expr> {
-        let node = self.factor();
+    // expr_0 = factor [ '^' expr_1 ]
+    //
+    // '^' binds tighter than a unary operator on its left, but its right
+    // operand may be a unary expression, so `-2^-2` is `-(2^(-2))`
+    fn expr_0(&mut self) -> Result<Box<Expr>> {
+        let node = self.factor()?;
 
         if self.matches(TokenKind::Pow) {
-            self.eat(TokenKind::Pow);
+            self.eat(TokenKind::Pow)?;
+
+            self.enter_level()?;
+            let right = self.expr_1()?;
+            self.leave_level();
 
-            Box::new(Expr::BinOp {
-                op: TokenKind::Pow,
-                left: node,
-                right: self.expr_0()
-            })
+            Ok(Self::bin_op(TokenKind::Pow, node, right))
         } else {
-            node
+            Ok(node)
         }
     }
 
-    // factor = Ident | Number | String | '(' expr ')' | False | True
-    //        | function_call
-    fn factor(&mut self) -> Box<Expr> {
-        let node = match self.tok.kind {
-            TokenKind::Ident => {
-                if self.peek() == TokenKind::Lpar {
-                    self.function_call()
-                } else {
-                    Box::new(Expr::Ident(Ident {
-                        name: self.tok.value.clone().unwrap().clone()
-                    }))
+    // factor = Number | String | False | True | Nil | '...' | 'function' func_body
+    //        | table | suffixed_expr
+    fn factor(&mut self) -> Result<Box<Expr>> {
+        let start = self.tok.loc;
+
+        let kind = match self.tok.kind {
+            TokenKind::Integer => {
+                let value = self.tok.text().unwrap().to_string();
+
+                match value.parse() {
+                    Ok(x) => ExprKind::Integer(x),
+                    Err(_) => return Err(self.error(format!("malformed number near '{}'", value)))
                 }
             },
-            TokenKind::Number => {
-                Box::new(Expr::Number(
-                    self.tok.value.clone().unwrap().parse().unwrap()
-                ))
+            TokenKind::Float => {
+                let value = self.tok.text().unwrap().to_string();
+
+                match value.parse() {
+                    Ok(x) => ExprKind::Number(x),
+                    Err(_) => return Err(self.error(format!("malformed number near '{}'", value)))
+                }
             },
             TokenKind::String => {
-                Box::new(Expr::String(
-                    self.tok.value.clone().unwrap().parse().unwrap()
-                ))
-            },
-            TokenKind::Lpar => {
-                self.eat(TokenKind::Lpar);
-                
-                self.expr()
+                ExprKind::String(
+                    self.tok.bytes().unwrap().to_vec()
+                )
             },
             TokenKind::False => {
-                Box::new(Expr::Boolean(false))
+                ExprKind::Boolean(false)
             },
             TokenKind::True => {
-                Box::new(Expr::Boolean(true))
+                ExprKind::Boolean(true)
+            },
+            TokenKind::Nil => {
+                ExprKind::Nil
+            },
+            TokenKind::Arg => {
+                ExprKind::Vararg
             },
+            TokenKind::Function => {
+                self.eat(TokenKind::Function)?;
+                let (args, is_vararg, body) = self.func_body(false)?;
+
+                return Ok(Box::new(Expr {
+                    kind: ExprKind::Function { args, is_vararg, body },
+                    span: self.span_from(start)
+                }));
+            },
+            TokenKind::Lbrc => return self.table(),
 
-            _ => panic!("Unexpected in factor: {:?}", self.tok.kind)
+            _ => return self.suffixed_expr()
         };
 
-        self.eat(self.tok.kind);
+        self.eat(self.tok.kind)?;
+
+        Ok(Box::new(Expr { kind, span: self.span_from(start) }))
+    }
+
+    // primary_expr = ident | '(' expr ')'
+    fn primary_expr(&mut self) -> Result<Box<Expr>> {
+        match self.tok.kind {
+            TokenKind::Ident => {
+                let ident = self.ident()?;
+                let span = ident.span;
+
+                Ok(Box::new(Expr { kind: ExprKind::Ident(ident), span }))
+            },
+            TokenKind::Lpar => {
+                let start = self.tok.loc;
+
+                self.eat(TokenKind::Lpar)?;
+                let node = self.expr()?;
+                self.eat(TokenKind::Rpar)?;
+
+                Ok(Box::new(Expr { kind: ExprKind::Paren(node), span: self.span_from(start) }))
+            },
+
+            _ => Err(self.error(format!("unexpected symbol near {}", self.tok)))
+        }
+    }
+
+    // suffixed_expr = primary_expr { '.' ident | '[' expr ']' | call_args | ':' ident call_args }
+    fn suffixed_expr(&mut self) -> Result<Box<Expr>> {
+        let start = self.tok.loc;
+        let mut node = self.primary_expr()?;
+
+        loop {
+            let kind = match self.tok.kind {
+                TokenKind::Dot => {
+                    self.eat(TokenKind::Dot)?;
+                    let ident = self.ident()?;
+
+                    ExprKind::Index {
+                        obj: node,
+                        key: Box::new(Expr { kind: ExprKind::String(ident.name.into_bytes()), span: ident.span })
+                    }
+                },
+                TokenKind::Lsqr => {
+                    self.eat(TokenKind::Lsqr)?;
+                    let key = self.expr()?;
+                    self.eat(TokenKind::Rsqr)?;
+
+                    ExprKind::Index { obj: node, key }
+                },
+                TokenKind::Lpar => {
+                    let args = self.call_args()?;
+
+                    ExprKind::FuncCall(FuncCall { func: node, method: None, args, span: self.span_from(start) })
+                },
+                TokenKind::Colon => {
+                    self.eat(TokenKind::Colon)?;
+                    let method = Some(self.ident()?);
+                    let args = self.call_args()?;
+
+                    ExprKind::FuncCall(FuncCall { func: node, method, args, span: self.span_from(start) })
+                },
+
+                _ => return Ok(node)
+            };
+
+            node = Box::new(Expr { kind, span: self.span_from(start) });
+        }
+    }
+
+    // call_args = '(' [ expr_list ] ')'
+    fn call_args(&mut self) -> Result<ExprList> {
+        self.eat(TokenKind::Lpar)?;
 
-        node
+        let mut args = vec![];
+        if !self.matches(TokenKind::Rpar) {
+            args = self.expr_list()?;
+        }
+
+        self.eat(TokenKind::Rpar)?;
+
+        Ok(args)
     }
 
-    // function_call = ident '(' expr_list ')'
-    fn function_call(&mut self) -> Box<Expr> {
-        let ident = self.ident();
-        self.eat(TokenKind::Lpar);
-        let args = self.expr_list();
+    // table = '{' [ field { ( ',' | ';' ) field } [ ',' | ';' ] ] '}'
+    // field = '[' expr ']' '=' expr | ident '=' expr | expr
+    fn table(&mut self) -> Result<Box<Expr>> {
+        let start = self.tok.loc;
+        self.eat(TokenKind::Lbrc)?;
+
+        let mut fields = vec![];
+        while !self.matches(TokenKind::Rbrc) {
+            let field = if self.matches(TokenKind::Lsqr) {
+                self.eat(TokenKind::Lsqr)?;
+                let key = self.expr()?;
+                self.eat(TokenKind::Rsqr)?;
+                self.eat(TokenKind::Assign)?;
+
+                Field::Pair(*key, *self.expr()?)
+            } else if self.matches(TokenKind::Ident) && self.peek() == TokenKind::Assign {
+                let ident = self.ident()?;
+                self.eat(TokenKind::Assign)?;
+
+                let key = Expr { kind: ExprKind::String(ident.name.into_bytes()), span: ident.span };
+                Field::Pair(key, *self.expr()?)
+            } else {
+                Field::Item(*self.expr()?)
+            };
+            fields.push(field);
+
+            if self.matches(TokenKind::Comma) || self.matches(TokenKind::Semi) {
+                self.eat(self.tok.kind)?;
+            } else {
+                break;
+            }
+        }
 
-        Box::new(Expr::FuncCall(FuncCall {
-            ident, args
-        }))
+        self.eat(TokenKind::Rbrc)?;
+
+        Ok(Box::new(Expr { kind: ExprKind::Table(fields), span: self.span_from(start) }))
+    }
+
+    // a binary operation spans from its left operand to its right one
+    fn bin_op(op: TokenKind, left: Box<Expr>, right: Box<Expr>) -> Box<Expr> {
+        let span = left.span.to(right.span);
+
+        Box::new(Expr {
+            kind: ExprKind::BinOp { op, left, right },
+            span
+        })
     }
 }
 
@@ -377,9 +793,101 @@ mod tests {
             function f(a, b)
                 e = a + b
             end
-        "#).analyze();
+        "#).analyze().unwrap();
+        let mut parser = Parser::new(toks);
+
+        println!("{:#?}", parser.parse().unwrap());
+    }
+
+    #[test]
+    fn unexpected_token() {
+        let toks = Lexer::new("if a = 1 then end").analyze().unwrap();
         let mut parser = Parser::new(toks);
 
-        println!("{:#?}", parser.parse());
+        match parser.parse() {
+            Err(Error::Parse { msg, .. }) => assert_eq!(msg, "expected 'then', found '='"),
+            res => panic!("expected a parse error, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let parse = |src: String| Parser::new(Lexer::new(&src).analyze().unwrap()).parse();
+
+        assert!(parse(format!("x = {}1", "- ".repeat(190))).is_ok());
+        assert_eq!(parse(format!("x = {}1", "- ".repeat(20000))).unwrap_err().msg(), "too many C levels (limit is 200) near '-'");
+        assert!(parse(format!("x = 1{}", " .. 1".repeat(20000))).is_err());
+
+        // the operands of a flat chain and the suffixes of a name are no levels
+        let sum = (1..20000).map(|i| format!(" + {}", i)).collect::<String>();
+        assert!(parse(format!("x = 0{}", sum)).is_ok());
+        assert!(parse(format!("x = a{}", " and a".repeat(20000))).is_ok());
+        assert!(parse(format!("x = a{}", ".x".repeat(20000))).is_ok());
+        assert!(parse(format!("f{}", "()".repeat(20000))).is_ok());
+
+        // nested parentheses and blocks take much more stack in a debug build,
+        // so give them as much as the main thread has
+        let deep = std::thread::Builder::new().stack_size(8 << 20).spawn(move || {
+            let parens = parse(format!("x = {}1{}", "(".repeat(20000), ")".repeat(20000)));
+            let blocks = parse(format!("{}{}", "do ".repeat(20000), "end ".repeat(20000)));
+
+            (parens.is_err(), blocks.is_err())
+        });
+        assert_eq!(deep.unwrap().join().unwrap(), (true, true));
+    }
+
+    #[test]
+    fn empty_stmt() {
+        let toks = Lexer::new(";a = 1; b = 2;; ; f(); do ; end return a;").analyze().unwrap();
+        let ast = Parser::new(toks).parse().unwrap();
+
+        assert_eq!(ast.len(), 5);
+        assert!(matches!(&ast[3].kind, StmtKind::Do(body) if body.is_empty()));
+        assert!(matches!(ast[4].kind, StmtKind::Return(_)));
+
+        let toks = Lexer::new("return 1;;").analyze().unwrap();
+        assert!(Parser::new(toks).parse().is_err());
+    }
+
+    #[test]
+    fn func_name() {
+        let toks = Lexer::new("function a.b:c(x) end").analyze().unwrap();
+        let ast = Parser::new(toks).parse().unwrap();
+
+        match &ast[0].kind {
+            StmtKind::FuncDecl { name, args, .. } => {
+                assert_eq!(name.ident.name, "a");
+                assert_eq!(name.fields.iter().map(|i| &i.name[..]).collect::<Vec<_>>(), ["b"]);
+                assert_eq!(name.method.as_ref().unwrap().name, "c");
+                assert_eq!(args.iter().map(|i| &i.name[..]).collect::<Vec<_>>(), ["self", "x"]);
+            },
+            kind => panic!("expected a function declaration, got {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn span() {
+        let toks = Lexer::new("x = 1\ny = -(x + 2) * f(3)").analyze().unwrap();
+        let ast = Parser::new(toks).parse().unwrap();
+
+        let offsets = |span: Span| (span.start.offset, span.end.offset);
+
+        assert_eq!(offsets(ast[1].span), (6, 25));
+
+        match &ast[1].kind {
+            StmtKind::Assign { targets, expr_list } => {
+                assert_eq!(offsets(targets[0].span), (6, 7));
+                assert_eq!(offsets(expr_list[0].span), (10, 25));
+
+                match &expr_list[0].kind {
+                    ExprKind::BinOp { left, right, .. } => {
+                        assert_eq!(offsets(left.span), (10, 18));
+                        assert_eq!(offsets(right.span), (21, 25));
+                    },
+                    kind => panic!("expected a binary operation, got {:?}", kind)
+                }
+            },
+            kind => panic!("expected an assignment, got {:?}", kind)
+        }
     }
 }
@@ -0,0 +1,356 @@
+use std::{collections::HashMap, hash::{Hash, Hasher}, rc::Rc};
+
+use super::{vm::Value, gc};
+
+// a lua table, the integer keys from 1 on are kept in a vector
+// and the others in a hash part which remembers the order of insertion,
+// so that a traversal can go on from any key
+#[derive(Debug, Default)]
+pub struct Table {
+    array: Vec<Value>,
+
+    entries: Vec<(Key, Value)>,
+    // the index of every key in `entries`
+    index: HashMap<Key, usize>,
+    // the entries set to nil, which stay until the next rebuild
+    removed: usize
+}
+
+// a value used as a key, floats holding an integer are turned into integers
+// so that 1 and 1.0 are the same key
+#[derive(Debug, Clone)]
+struct Key(Value);
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.0.raw_eq(&other.0)
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            Value::Integer(x) => x.hash(state),
+            Value::Number(x) => x.to_bits().hash(state),
+            Value::String(x) => x.hash(state),
+            Value::Boolean(x) => x.hash(state),
+            Value::Builtin(x) => (x.func as usize).hash(state),
+            Value::Function(x) => Rc::as_ptr(x).hash(state),
+            Value::Table(x) => Rc::as_ptr(x).hash(state),
+
+            // never a key
+            Value::Nil => {}
+        }
+    }
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table::default()
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        let key = normalize(key.clone());
+
+        if let Some(i) = self.array_index(&key) {
+            return self.array[i].clone();
+        }
+
+        match self.index.get(&Key(key)) {
+            Some(&i) => self.entries[i].1.clone(),
+            None => Value::Nil
+        }
+    }
+
+    // gives the message of the error for a nil or NaN key
+    pub fn set(&mut self, key: Value, val: Value) -> Result<(), String> {
+        let key = match normalize(key) {
+            Value::Nil => return Err("index is nil".to_string()),
+            Value::Number(x) if x.is_nan() => return Err("index is NaN".to_string()),
+
+            key => key
+        };
+
+        if let Some(i) = self.array_index(&key) {
+            // the slot stays even when set to nil, so that
+            // a traversal clearing the fields can go on
+            self.array[i] = val;
+
+            return Ok(());
+        }
+
+        // the key right after the array part makes it grow
+        if let Value::Integer(x) = key {
+            if x as usize == self.array.len() + 1 && !matches!(val, Value::Nil) {
+                self.array.push(val);
+                self.migrate();
+
+                return Ok(());
+            }
+        }
+
+        let key = Key(key);
+        match self.index.get(&key) {
+            Some(&i) => {
+                if matches!(val, Value::Nil) && !matches!(self.entries[i].1, Value::Nil) {
+                    self.removed += 1;
+                } else if !matches!(val, Value::Nil) && matches!(self.entries[i].1, Value::Nil) {
+                    self.removed -= 1;
+                }
+
+                self.entries[i].1 = val;
+            },
+            None => {
+                if matches!(val, Value::Nil) {
+                    return Ok(());
+                }
+
+                if self.removed > self.entries.len() / 2 {
+                    self.rebuild();
+                }
+
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, val));
+            }
+        }
+
+        Ok(())
+    }
+
+    // a border of the table, which is what `#` gives
+    //
+    // the keys after the array part are never in the hash part, so when its
+    // last item is nil, a border is found in it by a binary search between
+    // a non-nil item, or none, and a nil one
+    pub fn len(&self) -> i64 {
+        let mut j = self.array.len();
+        if !matches!(self.array.last(), Some(Value::Nil)) {
+            return j as i64;
+        }
+
+        let mut i = 0;
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if matches!(self.array[m - 1], Value::Nil) {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+
+        i as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the entry following the key in a traversal, nil starts it
+    //
+    // gives the message of the error for a key which is not in the table
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, String> {
+        let key = normalize(key.clone());
+
+        let (mut i, mut j) = match &key {
+            Value::Nil => (0, 0),
+            _ => match self.array_index(&key) {
+                Some(i) => (i + 1, 0),
+                None => match self.index.get(&Key(key)) {
+                    Some(&j) => (self.array.len(), j + 1),
+                    None => return Err("invalid key to 'next'".to_string())
+                }
+            }
+        };
+
+        while i < self.array.len() {
+            if !matches!(self.array[i], Value::Nil) {
+                return Ok(Some((Value::Integer(i as i64 + 1), self.array[i].clone())));
+            }
+            i += 1;
+        }
+
+        while j < self.entries.len() {
+            let (key, val) = &self.entries[j];
+            if !matches!(val, Value::Nil) {
+                return Ok(Some((key.0.clone(), val.clone())));
+            }
+            j += 1;
+        }
+
+        Ok(None)
+    }
+
+    // every value held, keys included, for the collector to follow
+    pub(super) fn values(&self) -> impl Iterator<Item = &Value> {
+        self.array.iter()
+            .chain(self.entries.iter().flat_map(|(key, val)| [&key.0, val]))
+            .chain(self.index.keys().map(|key| &key.0))
+    }
+
+    // empties the table and gives what it held
+    pub(super) fn take_values(&mut self) -> Vec<Value> {
+        self.index.clear();
+        self.removed = 0;
+
+        let mut res = std::mem::take(&mut self.array);
+        res.extend(self.entries.drain(..).flat_map(|(key, val)| [key.0, val]));
+
+        res
+    }
+
+    fn array_index(&self, key: &Value) -> Option<usize> {
+        match key {
+            Value::Integer(x) if *x >= 1 && *x as u64 <= self.array.len() as u64 => {
+                Some(*x as usize - 1)
+            },
+
+            _ => None
+        }
+    }
+
+    // moves the keys following the array part from the hash part into it
+    fn migrate(&mut self) {
+        loop {
+            let key = Key(Value::Integer(self.array.len() as i64 + 1));
+
+            let i = match self.index.get(&key) {
+                Some(&i) if !matches!(self.entries[i].1, Value::Nil) => i,
+                _ => break
+            };
+
+            let val = std::mem::replace(&mut self.entries[i].1, Value::Nil);
+            self.removed += 1;
+            self.array.push(val);
+        }
+    }
+
+    // drops the entries set to nil
+    fn rebuild(&mut self) {
+        self.entries.retain(|e| !matches!(e.1, Value::Nil));
+        self.index = self.entries.iter()
+            .enumerate()
+            .map(|(i, e)| (e.0.clone(), i))
+            .collect();
+        self.removed = 0;
+    }
+}
+
+// see `gc::release`
+impl Drop for Table {
+    fn drop(&mut self) {
+        gc::release(self.take_values());
+    }
+}
+
+fn normalize(key: Value) -> Value {
+    match key {
+        Value::Number(x) if x.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&x) => {
+            Value::Integer(x as i64)
+        },
+
+        key => key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_and_hash() {
+        let mut t = Table::new();
+
+        t.set(Value::Integer(2), Value::Integer(20)).unwrap();
+        assert_eq!(t.len(), 0);
+
+        // 1 grows the array part, which takes 2 from the hash part
+        t.set(Value::Number(1.0), Value::Integer(10)).unwrap();
+        assert_eq!(t.len(), 2);
+        assert!(matches!(t.get(&Value::Integer(1)), Value::Integer(10)));
+        assert!(matches!(t.get(&Value::Number(2.0)), Value::Integer(20)));
+
+        t.set(Value::from("x"), Value::Boolean(true)).unwrap();
+        assert!(matches!(t.get(&Value::from("x")), Value::Boolean(true)));
+        assert!(matches!(t.get(&Value::from("y")), Value::Nil));
+
+        t.set(Value::Integer(2), Value::Nil).unwrap();
+        assert_eq!(t.len(), 1);
+
+        // a border is still found once the array part ends with nils
+        for i in 3..=10 {
+            t.set(Value::Integer(i), Value::Integer(i)).unwrap();
+        }
+        t.set(Value::Integer(2), Value::Integer(2)).unwrap();
+        t.set(Value::Integer(10), Value::Nil).unwrap();
+        assert_eq!(t.len(), 9);
+        t.set(Value::Integer(6), Value::Nil).unwrap();
+        t.set(Value::Integer(9), Value::Nil).unwrap();
+        assert!([5, 8].contains(&t.len()));
+        for i in 1..=10 {
+            t.set(Value::Integer(i), Value::Nil).unwrap();
+        }
+        assert_eq!(t.len(), 0);
+
+        assert_eq!(t.set(Value::Nil, Value::Integer(1)), Err("index is nil".to_string()));
+        assert_eq!(t.set(Value::Number(f64::NAN), Value::Integer(1)), Err("index is NaN".to_string()));
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut t = Table::new();
+
+        for i in 0..100 {
+            t.set(Value::Integer(-i), Value::Integer(i)).unwrap();
+        }
+        for i in 0..100 {
+            t.set(Value::Integer(-i), Value::Nil).unwrap();
+        }
+        t.set(Value::Integer(-1), Value::Integer(1)).unwrap();
+        t.set(Value::Integer(-200), Value::Integer(200)).unwrap();
+
+        assert_eq!(t.entries.len(), 2);
+        assert!(matches!(t.get(&Value::Integer(-1)), Value::Integer(1)));
+        assert!(matches!(t.get(&Value::Integer(-2)), Value::Nil));
+        assert!(matches!(t.get(&Value::Integer(-200)), Value::Integer(200)));
+    }
+
+    #[test]
+    fn next() {
+        let mut t = Table::new();
+
+        t.set(Value::Integer(1), Value::Integer(10)).unwrap();
+        t.set(Value::from("x"), Value::Integer(20)).unwrap();
+        t.set(Value::from("y"), Value::Integer(30)).unwrap();
+        t.set(Value::from("x"), Value::Nil).unwrap();
+
+        let mut keys = vec![];
+        let mut key = Value::Nil;
+        while let Some((k, _)) = t.next(&key).unwrap() {
+            keys.push(k.to_string());
+            key = k;
+        }
+        assert_eq!(keys, ["1", "y"]);
+
+        // a key set to nil during the traversal can still be followed
+        assert!(t.next(&Value::from("x")).is_ok());
+        assert_eq!(t.next(&Value::Integer(5)).unwrap_err(), "invalid key to 'next'");
+
+        // so can the keys of the array part, the last ones included
+        let mut t = Table::new();
+        for i in 1..=3 {
+            t.set(Value::Integer(i), Value::Integer(i)).unwrap();
+        }
+
+        let mut keys = vec![];
+        let mut key = Value::Nil;
+        while let Some((k, _)) = t.next(&key).unwrap() {
+            t.set(k.clone(), Value::Nil).unwrap();
+            keys.push(k.to_string());
+            key = k;
+        }
+        assert_eq!(keys, ["1", "2", "3"]);
+        assert!(t.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
+use std::fmt;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TokenKind {
-    Number,
+    Integer,
+    Float,
     Ident,
     String,
 
@@ -26,6 +29,7 @@ pub enum TokenKind {
     Arg,        // ...
     Comma,      // ,
     Colon,      // :
+    DoubleColon,// ::
     Semi,       // ;
 
     Eq,         // ==
@@ -41,11 +45,20 @@ pub enum TokenKind {
     Then,
     Do,
     While,
+    Repeat,
+    Until,
+    For,
+    In,
+    Break,
+    Goto,
     End,
     Function,
+    Return,
+    Local,
 
     True,
     False,
+    Nil,
     And,
     Not,
     Or,
@@ -53,30 +66,180 @@ pub enum TokenKind {
     Eof
 }
 
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TokenKind::Integer  |
+            TokenKind::Float    => "number",
+            TokenKind::Ident    => "name",
+            TokenKind::String   => "string",
+
+            TokenKind::Plus     => "'+'",
+            TokenKind::Minus    => "'-'",
+            TokenKind::Mul      => "'*'",
+            TokenKind::Pow      => "'^'",
+            TokenKind::RealDiv  => "'/'",
+            TokenKind::IntDiv   => "'//'",
+            TokenKind::Mod      => "'%'",
+            TokenKind::Concat   => "'..'",
+            TokenKind::Len      => "'#'",
+
+            TokenKind::Lpar     => "'('",
+            TokenKind::Rpar     => "')'",
+            TokenKind::Lsqr     => "'['",
+            TokenKind::Rsqr     => "']'",
+            TokenKind::Lbrc     => "'{'",
+            TokenKind::Rbrc     => "'}'",
+
+            TokenKind::Dot      => "'.'",
+            TokenKind::Assign   => "'='",
+            TokenKind::Arg      => "'...'",
+            TokenKind::Comma    => "','",
+            TokenKind::Colon    => "':'",
+            TokenKind::DoubleColon => "'::'",
+            TokenKind::Semi     => "';'",
+
+            TokenKind::Eq       => "'=='",
+            TokenKind::UnEq     => "'~='",
+            TokenKind::Lt       => "'<'",
+            TokenKind::Le       => "'<='",
+            TokenKind::Gt       => "'>'",
+            TokenKind::Ge       => "'>='",
+
+            TokenKind::If       => "'if'",
+            TokenKind::Else     => "'else'",
+            TokenKind::Elseif   => "'elseif'",
+            TokenKind::Then     => "'then'",
+            TokenKind::Do       => "'do'",
+            TokenKind::While    => "'while'",
+            TokenKind::Repeat   => "'repeat'",
+            TokenKind::Until    => "'until'",
+            TokenKind::For      => "'for'",
+            TokenKind::In       => "'in'",
+            TokenKind::Break    => "'break'",
+            TokenKind::Goto     => "'goto'",
+            TokenKind::End      => "'end'",
+            TokenKind::Function => "'function'",
+            TokenKind::Return   => "'return'",
+            TokenKind::Local    => "'local'",
+
+            TokenKind::True     => "'true'",
+            TokenKind::False    => "'false'",
+            TokenKind::Nil      => "'nil'",
+            TokenKind::And      => "'and'",
+            TokenKind::Not      => "'not'",
+            TokenKind::Or       => "'or'",
+
+            TokenKind::Eof      => "<eof>"
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Location {
-    line: i32,
-    column: i32
+    pub line: usize,
+    pub column: usize,
+    // in bytes, from the start of the source
+    pub offset: usize
+}
+
+impl Default for Location {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Location {
     pub fn new() -> Location {
-        Location { line: 0, column: 1 }
+        Location { line: 1, column: 1, offset: 0 }
     }
 
-    pub fn advance(&mut self) {
+    // moves past a char taking `width` bytes
+    pub fn advance(&mut self, width: usize) {
         self.column += 1;
+        self.offset += width;
     }
 
-    pub fn new_line(&mut self) {
+    // moves past a line break taking `width` bytes
+    pub fn new_line(&mut self, width: usize) {
         self.line += 1;
         self.column = 1;
+        self.offset += width;
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// a range of the source, from `start` up to but not including `end`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location
+}
+
+impl Span {
+    pub fn new(start: Location, end: Location) -> Span {
+        Span { start, end }
+    }
+
+    // the span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span { start: self.start, end: other.end }
+    }
+}
+
+// the text of a name or a numeral, or the contents of a string,
+// which may be any bytes, as lua strings are
+#[derive(Debug, PartialEq, Clone)]
+pub enum TokenValue {
+    Text(String),
+    Bytes(Vec<u8>)
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub kind: TokenKind,
-    pub value: Option<String>,
-    pub loc: Location
-}
\ No newline at end of file
+    pub value: Option<TokenValue>,
+    pub loc: Location,
+    // where the token ends, exclusive
+    pub end: Location
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        Span::new(self.loc, self.end)
+    }
+
+    // the text of a name or a numeral
+    pub fn text(&self) -> Option<&str> {
+        match &self.value {
+            Some(TokenValue::Text(x)) => Some(x),
+            _ => None
+        }
+    }
+
+    // the contents of a string
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.value {
+            Some(TokenValue::Bytes(x)) => Some(x),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(TokenValue::Bytes(value)) => write!(f, "'\"{}\"'", String::from_utf8_lossy(value)),
+            Some(TokenValue::Text(value)) => write!(f, "'{}'", value),
+
+            None => write!(f, "{}", self.kind)
+        }
+    }
+}
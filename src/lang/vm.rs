@@ -1,59 +1,381 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
 
-use super::bytecode::{Bytecodes, Instruction::*};
+use super::{bytecode::{Bytecodes, Instruction::*}, error::{Error, Result}, number::{Number, ArithOp}, builtin::{self, Builtin}, table::Table, gc::{self, Object}};
 
 #[derive(Debug, Clone)]
 pub enum Value {
+    Integer(i64),
     Number(f64),
-    String(String),
+    // lua strings are bytes, which need not be utf-8
+    String(Vec<u8>),
     Boolean(bool),
+    Builtin(Builtin),
+    Function(Rc<Closure>),
+    Table(Rc<RefCell<Table>>),
 
     Nil
 }
 
+// a variable of an enclosing function, which stays on the stack while
+// that function runs and moves into the upvalue once its scope ends
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value)
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    proto: Rc<Bytecodes>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>
+}
+
+impl Closure {
+    fn new(proto: Bytecodes) -> Closure {
+        Closure { proto: Rc::new(proto), upvalues: vec![] }
+    }
+
+    pub(super) fn upvalues(&self) -> &[Rc<RefCell<Upvalue>>] {
+        &self.upvalues
+    }
+
+    // drops the upvalues, and gives the values of those only used here
+    pub(super) fn take_values(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.upvalues).into_iter()
+            .filter_map(|upvalue| Rc::try_unwrap(upvalue).ok())
+            .filter_map(|upvalue| match upvalue.into_inner() {
+                Upvalue::Closed(val) => Some(val),
+                Upvalue::Open(_) => None
+            })
+            .collect()
+    }
+}
+
+// see `gc::release`
+impl Drop for Closure {
+    fn drop(&mut self) {
+        gc::release(self.take_values());
+    }
+}
+
+impl Value {
+    // the name of the type, as given by lua's `type`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "boolean",
+            Value::Builtin(_) | Value::Function(_) => "function",
+            Value::Table(_) => "table",
+
+            Value::Nil => "nil"
+        }
+    }
+
+    // only nil and false are false
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    // the primitive equality, which never calls a metamethod
+    pub fn raw_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Builtin(a), Value::Builtin(b)) => a.func as usize == b.func as usize,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Nil, Value::Nil) => true,
+
+            (a, b) => match (a.to_number(), b.to_number()) {
+                (Some(a), Some(b)) => Number::equal(a, b),
+                _ => false
+            }
+        }
+    }
+
+    pub fn to_number(&self) -> Option<Number> {
+        match self {
+            Value::Integer(x) => Some(Number::Integer(*x)),
+            Value::Number(x) => Some(Number::Float(*x)),
+
+            _ => None
+        }
+    }
+
+    // the number used by arithmetic, strings holding a numeral are converted
+    pub fn to_arith_number(&self) -> Option<Number> {
+        match self {
+            Value::String(x) => Number::parse(std::str::from_utf8(x).ok()?),
+
+            val => val.to_number()
+        }
+    }
+
+    // the bytes of a string, and the text of any other value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Value::String(x) => x.clone(),
+
+            val => val.to_string().into_bytes()
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(x) => write!(f, "{}", Number::Integer(*x)),
+            Value::Number(x) => write!(f, "{}", Number::Float(*x)),
+            Value::String(x) => write!(f, "{}", String::from_utf8_lossy(x)),
+            Value::Boolean(x) => write!(f, "{}", x),
+            Value::Builtin(x) => write!(f, "function: builtin: {:p}", x.func as *const ()),
+            Value::Function(x) => write!(f, "function: {:p}", Rc::as_ptr(x)),
+            Value::Table(x) => write!(f, "table: {:p}", Rc::as_ptr(x)),
+
+            Value::Nil => write!(f, "nil")
+        }
+    }
+}
+
+// converts the limit of an integer loop to an integer, clipping floats,
+// gives None when the loop must not run at all
+fn for_limit(limit: Number, step: i64) -> Option<i64> {
+    match limit {
+        Number::Integer(x) => Some(x),
+        Number::Float(x) => {
+            if x.is_nan() {
+                return None;
+            }
+
+            let x = if step > 0 { x.floor() } else { x.ceil() };
+            if x >= 9223372036854775808.0 {
+                // too big, the loop runs up to the largest integer, or never when going down
+                if step > 0 { Some(i64::MAX) } else { None }
+            } else if x < -9223372036854775808.0 {
+                if step > 0 { None } else { Some(i64::MIN) }
+            } else {
+                Some(x as i64)
+            }
+        }
+    }
+}
+
+impl From<Table> for Value {
+    fn from(x: Table) -> Value {
+        let t = Rc::new(RefCell::new(x));
+        gc::track(Object::Table(Rc::downgrade(&t)));
+
+        Value::Table(t)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(x: &str) -> Value {
+        Value::String(x.as_bytes().to_vec())
+    }
+}
+
+impl From<Number> for Value {
+    fn from(x: Number) -> Value {
+        match x {
+            Number::Integer(x) => Value::Integer(x),
+            Number::Float(x) => Value::Number(x)
+        }
+    }
+}
+
+// lua raises "stack overflow" past about this many nested calls
+const MAX_CALL_DEPTH: usize = 200000;
+
+// what is needed to go back to a caller once its callee returns
 pub struct Frame {
-    parent: Box<Frame>,
-    body: Bytecodes,
-    env: HashMap<String, Value>
+    closure: Rc<Closure>,
+    p: usize,
+    base: usize,
+    varargs: Vec<Value>
 }
 
 pub struct VirtualMachine {
-    codes: Bytecodes,
+    closure: Rc<Closure>,
     p: usize,
+    // where the locals of the running function start on the stack
+    base: usize,
 
     stack: Vec<Value>,
     memory: HashMap<String, Value>,
 
-    call_stack: Vec<Frame>
+    call_stack: Vec<Frame>,
+    // the number of results of the last call
+    nres: usize,
+    // the extra arguments of the running function, for `...`
+    varargs: Vec<Value>,
+    // the upvalues still pointing to the stack, shared by every closure using them
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>
 }
 
 impl VirtualMachine {
     pub fn new(codes: Bytecodes) -> VirtualMachine {
+        let mut memory = HashMap::new();
+        builtin::load(&mut memory);
+
         VirtualMachine {
-            codes,
+            closure: Rc::new(Closure::new(codes)),
             p: 0,
+            base: 0,
             stack: vec![],
-            memory: HashMap::new(),
-            call_stack: vec![]
+            memory,
+            call_stack: vec![],
+            nres: 0,
+            varargs: vec![],
+            open_upvalues: vec![]
         }
     }
 
-    pub fn run(&mut self) {
+    // replaces the code to run while keeping the globals,
+    // so that one vm could run many chunks in turn
+    pub fn load(&mut self, codes: Bytecodes) {
+        // a chunk stopped by an error may leave some upvalues open
+        self.close_upvalues(0);
+
+        self.closure = Rc::new(Closure::new(codes));
+        self.p = 0;
+        self.base = 0;
+        self.stack.clear();
+        self.call_stack.clear();
+        self.open_upvalues.clear();
+        self.varargs.clear();
+    }
+
+    // runs until the end of the code and returns the values left on the stack
+    pub fn run(&mut self) -> Result<Vec<Value>> {
         loop {
-            let code = self.codes.bc[self.p];
+            let code = self.codes().bc[self.p];
 
             match code.inst {
                 LoadNumber => {
-                    self.stack.push(Value::Number(self.codes.nums[code.arg]));
+                    let val = self.codes().nums[code.arg].into();
+                    self.stack.push(val);
+                },
+                LoadString => {
+                    let val = Value::String(self.codes().strs[code.arg].clone());
+                    self.stack.push(val);
                 },
 
+                // an unset global is nil, and setting one to nil removes it
                 LoadGlob => {
-                    let name = &self.codes.idents[code.arg];
-                    self.stack.push(self.memory.get(name).unwrap().clone());
+                    let name = &self.codes().idents[code.arg];
+                    let val = self.memory.get(name).cloned().unwrap_or(Value::Nil);
+
+                    self.stack.push(val);
                 },
                 StoreGlob => {
-                    let name = &self.codes.idents[code.arg];
-                    self.memory.insert(name.clone(), self.stack.pop().unwrap());
+                    let val = self.pop()?;
+                    let name = self.codes().idents[code.arg].clone();
+
+                    match val {
+                        Value::Nil => self.memory.remove(&name),
+                        val => self.memory.insert(name, val)
+                    };
+                },
+                // the slots of the locals start at the base of the frame
+                LoadLocal => {
+                    let val = self.stack[self.base + code.arg].clone();
+                    self.stack.push(val);
+                },
+                StoreLocal => {
+                    let val = self.pop()?;
+                    self.stack[self.base + code.arg] = val;
+                },
+                LoadUpvalue => {
+                    let val = match &*self.closure.upvalues[code.arg].borrow() {
+                        Upvalue::Open(idx) => self.stack[*idx].clone(),
+                        Upvalue::Closed(val) => val.clone()
+                    };
+
+                    self.stack.push(val);
+                },
+                StoreUpvalue => {
+                    let val = self.pop()?;
+
+                    match &mut *self.closure.upvalues[code.arg].borrow_mut() {
+                        Upvalue::Open(idx) => self.stack[*idx] = val,
+                        Upvalue::Closed(x) => *x = val
+                    }
+                },
+                CloseUpvalues => {
+                    self.close_upvalues(self.base + code.arg);
+                },
+                // the values are counted in `nres`, like the results of a call
+                LoadVararg => {
+                    self.nres = self.varargs.len();
+                    self.stack.extend(self.varargs.iter().cloned());
+                },
+                NewTable => {
+                    self.stack.push(Table::new().into());
+                    gc::step();
+                },
+                GetIndex => {
+                    let key = self.pop()?;
+                    let obj = self.pop()?;
+
+                    let val = self.get_index(&obj, &key)?;
+                    self.stack.push(val);
+                },
+                GetField => {
+                    let key = Value::String(self.codes().strs[code.arg].clone());
+                    let obj = self.pop()?;
+
+                    let val = self.get_index(&obj, &key)?;
+                    self.stack.push(val);
+                },
+                // replaces the object with its method and the object itself
+                LoadMethod => {
+                    let key = Value::String(self.codes().strs[code.arg].clone());
+                    let obj = self.pop()?;
+
+                    let method = self.get_index(&obj, &key)?;
+                    self.stack.push(method);
+                    self.stack.push(obj);
+                },
+                // the table and the key are below the value and `arg` other values
+                SetIndex => {
+                    let val = self.pop()?;
+
+                    let top = self.stack.len() - code.arg;
+                    let key = self.stack.remove(top - 1);
+                    let obj = self.stack.remove(top - 2);
+
+                    self.set_index(&obj, key, val)?;
+                },
+                SetField => {
+                    let val = self.pop()?;
+                    let obj = self.pop()?;
+                    let key = Value::String(self.codes().strs[code.arg].clone());
+
+                    self.set_index(&obj, key, val)?;
+                },
+                // sets a field of a table being made, which is below `arg` items
+                InitIndex => {
+                    let val = self.pop()?;
+                    let key = self.pop()?;
+                    let obj = self.stack[self.stack.len() - code.arg - 1].clone();
+
+                    self.set_index(&obj, key, val)?;
+                },
+                // SetListVar, FuncCallVar and ReturnVar count the results
+                // of the call on top of the stack as well
+                SetList | SetListVar => {
+                    let count = if let SetListVar = code.inst { code.arg + self.nres } else { code.arg };
+                    let items = self.stack.split_off(self.stack.len() - count);
+
+                    if let Some(Value::Table(t)) = self.stack.last() {
+                        let mut t = t.borrow_mut();
+
+                        for (i, val) in items.into_iter().enumerate() {
+                            t.set(Value::Integer(i as i64 + 1), val).unwrap();
+                        }
+                    }
                 },
 
                 LoadTrue => {
@@ -62,89 +384,1361 @@ impl VirtualMachine {
                 LoadFalse => {
                     self.stack.push(Value::Boolean(false));
                 },
+                LoadNil => {
+                    self.stack.push(Value::Nil);
+                },
 
-                BinAdd => {
-                    let right = self.pop_num();
-                    let left = self.pop_num();
+                BinAdd => self.arith(ArithOp::Add)?,
+                BinMinus => self.arith(ArithOp::Sub)?,
+                BinMul => self.arith(ArithOp::Mul)?,
+                BinRealDiv => self.arith(ArithOp::Div)?,
+                BinIntDiv => self.arith(ArithOp::IntDiv)?,
+                BinMod => self.arith(ArithOp::Mod)?,
+                BinPow => self.arith(ArithOp::Pow)?,
+                UnaryMinus => {
+                    let val = self.pop()?;
+                    let x = val.to_arith_number().ok_or_else(|| self.operand_error(
+                        format!("attempt to perform arithmetic on a {} value", val.type_name()), 0
+                    ))?;
+                    let res = Number::arith(ArithOp::Neg, x, x).unwrap();
 
-                    self.stack.push(Value::Number(left + right));
+                    self.stack.push(res.into());
                 },
-                BinMinus => {
-                    let right = self.pop_num();
-                    let left = self.pop_num();
+                UnaryNot => {
+                    let val = self.pop()?;
+                    self.stack.push(Value::Boolean(!val.truthy()));
+                },
+                UnaryLen => {
+                    let val = match self.pop()? {
+                        Value::String(x) => Value::Integer(x.len() as i64),
+                        Value::Table(t) => Value::Integer(t.borrow().len()),
+
+                        val => return Err(self.operand_error(
+                            format!("attempt to get length of a {} value", val.type_name()), 0
+                        ))
+                    };
 
-                    self.stack.push(Value::Number(left - right));
+                    self.stack.push(val);
                 },
-                BinMul => {
-                    let right = self.pop_num();
-                    let left = self.pop_num();
 
-                    self.stack.push(Value::Number(left * right));
+                BinConcat => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+
+                    let val = match (left, right) {
+                        (left @ (Value::String(_) | Value::Integer(_) | Value::Number(_)),
+                            right @ (Value::String(_) | Value::Integer(_) | Value::Number(_))) => {
+                            let mut res = left.to_bytes();
+                            res.extend(right.to_bytes());
+
+                            Value::String(res)
+                        },
+
+                        (Value::String(_) | Value::Integer(_) | Value::Number(_), val) => {
+                            return Err(self.operand_error(
+                                format!("attempt to concatenate a {} value", val.type_name()), 1
+                            ));
+                        },
+                        (val, _) => {
+                            return Err(self.operand_error(
+                                format!("attempt to concatenate a {} value", val.type_name()), 0
+                            ));
+                        }
+                    };
+
+                    self.stack.push(val);
                 },
-                BinIntDiv => {
-                    let right = self.pop_num();
-                    let left = self.pop_num();
 
-                    self.stack.push(Value::Number((left / right).floor()));
+                // `a > b` is `b < a` and `a >= b` is `b <= a`, marked by an arg of 1
+                BinLt => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    let (left, right) = if code.arg == 1 { (right, left) } else { (left, right) };
+
+                    let res = self.less_than(&left, &right)?;
+                    self.stack.push(Value::Boolean(res));
                 },
-                BinRealDiv => {
-                    let right = self.pop_num();
-                    let left = self.pop_num();
+                BinLe => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    let (left, right) = if code.arg == 1 { (right, left) } else { (left, right) };
 
-                    self.stack.push(Value::Number(left / right));
+                    let res = self.less_equal(&left, &right)?;
+                    self.stack.push(Value::Boolean(res));
                 },
-                
-                BinLt => {
-                    let right = self.pop_num();
-                    let left = self.pop_num();
+                BinEq => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
 
-                    self.stack.push(Value::Boolean(left < right));
-                }
+                    self.stack.push(Value::Boolean(left.raw_eq(&right)));
+                },
 
                 JumpAbsoluteIfFalse => {
-                    if !match self.stack.pop().unwrap() {
-                        Value::Boolean(x) => x,
-                        Value::Nil => false,
-
-                        _ => true
-                    } {
+                    if !self.pop()?.truthy() {
                         self.p = code.arg;
+                        continue;
                     }
                 },
+                JumpAbsoluteIfFalseOrPop => {
+                    if !self.stack.last().unwrap().truthy() {
+                        self.p = code.arg;
+                        continue;
+                    }
+                    self.stack.pop();
+                },
+                JumpAbsoluteIfTrueOrPop => {
+                    if self.stack.last().unwrap().truthy() {
+                        self.p = code.arg;
+                        continue;
+                    }
+                    self.stack.pop();
+                },
                 JumpAbsolute => {
                     self.p = code.arg;
+                    continue;
+                },
+                // jumps to the end when the loop runs zero times
+                ForPrep => {
+                    if !self.for_prep()? {
+                        self.p = code.arg;
+                        continue;
+                    }
+                },
+                // ends a generic for loop when the first value of the iterator is nil
+                ForIter => {
+                    if let Some(Value::Nil) = self.stack.last() {
+                        self.stack.pop();
+                        self.p = code.arg;
+                        continue;
+                    }
+                },
+                // jumps back to the body while the loop goes on
+                ForLoop => {
+                    if self.for_loop() {
+                        self.p = code.arg;
+                        continue;
+                    }
                 },
 
                 FuncDecl => {
-                    
-                }
+                    let proto = self.codes().protos[code.arg].clone();
+                    let upvalues = proto.upvalues.iter().map(|desc| {
+                        if desc.in_stack {
+                            self.find_upvalue(self.base + desc.idx)
+                        } else {
+                            self.closure.upvalues[desc.idx].clone()
+                        }
+                    }).collect();
+
+                    let closure = Rc::new(Closure { proto, upvalues });
+                    gc::track(Object::Function(Rc::downgrade(&closure)));
+
+                    self.stack.push(Value::Function(closure));
+                    gc::step();
+                },
+                // the callee is below its arguments, the number of which is the arg,
+                // they are all replaced with the results, which are counted in `nres`
+                FuncCall | FuncCallVar => {
+                    let argc = if let FuncCallVar = code.inst { code.arg + self.nres } else { code.arg };
+                    let callee = self.stack.len() - argc - 1;
+
+                    match self.stack[callee].clone() {
+                        Value::Builtin(b) => {
+                            let args = self.stack.split_off(callee + 1);
+                            let res = (b.func)(args).map_err(|msg| self.error(msg))?;
+
+                            self.stack.truncate(callee);
+                            self.nres = res.len();
+                            self.stack.extend(res);
+
+                            // a builtin may have made tables
+                            gc::step();
+                        },
+                        Value::Function(closure) => {
+                            if self.call_stack.len() >= MAX_CALL_DEPTH {
+                                return Err(self.error("stack overflow".to_string()));
+                            }
 
-                End => break,
+                            // extra arguments are dropped, or kept aside for a vararg
+                            // function, and missing ones are nil
+                            let params = callee + 1 + closure.proto.params;
+                            let varargs = if closure.proto.is_vararg && self.stack.len() > params {
+                                self.stack.split_off(params)
+                            } else {
+                                vec![]
+                            };
+                            self.stack.resize(params, Value::Nil);
 
-                _ => unimplemented!()
+                            let frame = Frame {
+                                closure: std::mem::replace(&mut self.closure, closure),
+                                p: self.p,
+                                base: self.base,
+                                varargs: std::mem::replace(&mut self.varargs, varargs)
+                            };
+                            self.call_stack.push(frame);
+
+                            self.p = 0;
+                            self.base = callee + 1;
+                            continue;
+                        },
+
+                        val => return Err(self.operand_error(
+                            format!("attempt to call a {} value", val.type_name()), 0
+                        ))
+                    }
+                },
+                // the values on top of the stack are the results
+                Return | ReturnVar => {
+                    let count = if let ReturnVar = code.inst { code.arg + self.nres } else { code.arg };
+                    let res = self.stack.split_off(self.stack.len() - count);
+
+                    // returning from the chunk ends the run
+                    let frame = match self.call_stack.pop() {
+                        Some(frame) => frame,
+                        None => {
+                            self.close_upvalues(0);
+                            return Ok(res);
+                        }
+                    };
+
+                    // the callee goes with its frame
+                    self.close_upvalues(self.base);
+                    self.stack.truncate(self.base - 1);
+                    self.nres = res.len();
+                    self.stack.extend(res);
+
+                    self.closure = frame.closure;
+                    self.p = frame.p;
+                    self.base = frame.base;
+                    self.varargs = frame.varargs;
+                },
+                // keeps `arg` values of the results of the last call
+                Adjust => {
+                    let len = self.stack.len() - self.nres + code.arg;
+                    self.stack.resize(len, Value::Nil);
+                },
+                Pop => {
+                    self.stack.truncate(self.stack.len() - code.arg);
+                },
+
+                End => break
             }
 
             self.p += 1;
         }
 
-        println!("{:?}", self.memory);
+        Ok(std::mem::take(&mut self.stack))
+    }
+
+    pub fn set_global(&mut self, name: &str, val: Value) {
+        self.memory.insert(name.to_string(), val);
+    }
+
+    // the values of `...` in the chunk
+    pub fn set_varargs(&mut self, args: Vec<Value>) {
+        self.varargs = args;
+    }
+
+    fn codes(&self) -> &Bytecodes {
+        &self.closure.proto
+    }
+
+    // gives the open upvalue for a stack slot, making it if there is none,
+    // so that the closures capturing the same local share it
+    fn find_upvalue(&mut self, idx: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if matches!(*upvalue.borrow(), Upvalue::Open(i) if i == idx) {
+                return upvalue.clone();
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(idx)));
+        gc::track(Object::Upvalue(Rc::downgrade(&upvalue)));
+        self.open_upvalues.push(upvalue.clone());
+
+        upvalue
+    }
+
+    // moves the values of the slots from `level` on into their upvalues
+    fn close_upvalues(&mut self, level: usize) {
+        let stack = &self.stack;
+
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+
+            match *upvalue {
+                Upvalue::Open(idx) if idx >= level => {
+                    *upvalue = Upvalue::Closed(stack[idx].clone());
+                    false
+                },
+                _ => true
+            }
+        });
+    }
+
+    fn pop(&mut self) -> Result<Value> {
+        match self.stack.pop() {
+            Some(val) => Ok(val),
+            None => Err(self.error("stack underflow".to_string()))
+        }
+    }
+
+    // an error raised by the code being run
+    fn error(&self, msg: String) -> Error {
+        Error::Runtime { span: self.codes().spans[self.p], msg }
+    }
+
+    // an error about an operand of the current code, naming it when it is
+    // a variable or a constant, as in "attempt to call a nil value (global 'f')"
+    fn operand_error(&self, msg: String, operand: usize) -> Error {
+        match self.codes().names.get(&(self.p, operand)) {
+            Some(info) => self.error(format!("{} ({})", msg, info)),
+            None => self.error(msg)
+        }
+    }
+
+    // checks the state of a numeric for loop on top of the stack, and puts
+    // the variable for the first iteration above it if there is one
+    //
+    // an integer loop keeps the number of iterations left in place of the limit,
+    // so that it can never overflow
+    fn for_prep(&mut self) -> Result<bool> {
+        let top = self.stack.len();
+        let (init, limit, step) = (&self.stack[top - 3], &self.stack[top - 2], &self.stack[top - 1]);
+
+        let init = init.to_number().ok_or_else(|| self.error("'for' initial value must be a number".to_string()))?;
+        let limit = limit.to_number().ok_or_else(|| self.error("'for' limit must be a number".to_string()))?;
+        let step = step.to_number().ok_or_else(|| self.error("'for' step must be a number".to_string()))?;
+
+        if let (Number::Integer(init), Number::Integer(step)) = (init, step) {
+            if step == 0 {
+                return Err(self.error("'for' step is zero".to_string()));
+            }
+
+            let limit = match for_limit(limit, step) {
+                Some(limit) => limit,
+                None => return Ok(false)
+            };
+            if if step > 0 { init > limit } else { init < limit } {
+                return Ok(false);
+            }
+
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(init as u64) / step as u64
+            } else {
+                // -(step + 1) + 1 avoids overflowing on i64::MIN
+                (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+            };
+
+            self.stack[top - 2] = Value::Integer(count as i64);
+            self.stack.push(Value::Integer(init));
+        } else {
+            let (init, limit, step) = (init.to_f64(), limit.to_f64(), step.to_f64());
+            if step == 0.0 {
+                return Err(self.error("'for' step is zero".to_string()));
+            }
+            let runs = if step > 0.0 { init <= limit } else { limit <= init };
+            if !runs {
+                return Ok(false);
+            }
+
+            self.stack[top - 3] = Value::Number(init);
+            self.stack[top - 2] = Value::Number(limit);
+            self.stack[top - 1] = Value::Number(step);
+            self.stack.push(Value::Number(init));
+        }
+
+        Ok(true)
+    }
+
+    fn for_loop(&mut self) -> bool {
+        let top = self.stack.len();
+
+        match (&self.stack[top - 3], &self.stack[top - 2], &self.stack[top - 1]) {
+            (&Value::Integer(index), &Value::Integer(count), &Value::Integer(step)) => {
+                if count == 0 {
+                    return false;
+                }
+
+                let index = index.wrapping_add(step);
+                self.stack[top - 3] = Value::Integer(index);
+                self.stack[top - 2] = Value::Integer((count as u64 - 1) as i64);
+                self.stack.push(Value::Integer(index));
+            },
+            (&Value::Number(index), &Value::Number(limit), &Value::Number(step)) => {
+                let index = index + step;
+                if if step > 0.0 { index > limit } else { index < limit } {
+                    return false;
+                }
+
+                self.stack[top - 3] = Value::Number(index);
+                self.stack.push(Value::Number(index));
+            },
+
+            _ => return false
+        }
+
+        true
+    }
+
+    fn get_index(&self, obj: &Value, key: &Value) -> Result<Value> {
+        match obj {
+            Value::Table(t) => Ok(t.borrow().get(key)),
+
+            _ => Err(self.operand_error(
+                format!("attempt to index a {} value", obj.type_name()), 0
+            ))
+        }
+    }
+
+    fn set_index(&self, obj: &Value, key: Value, val: Value) -> Result<()> {
+        match obj {
+            Value::Table(t) => t.borrow_mut().set(key, val).map_err(|msg| self.error(msg)),
+
+            _ => Err(self.operand_error(
+                format!("attempt to index a {} value", obj.type_name()), 0
+            ))
+        }
+    }
+
+    fn less_than(&self, left: &Value, right: &Value) -> Result<bool> {
+        match (left, right) {
+            (Value::String(a), Value::String(b)) => Ok(a < b),
+
+            (a, b) => match (a.to_number(), b.to_number()) {
+                (Some(a), Some(b)) => Ok(Number::less_than(a, b)),
+                _ => Err(self.compare_error(a, b))
+            }
+        }
+    }
+
+    fn less_equal(&self, left: &Value, right: &Value) -> Result<bool> {
+        match (left, right) {
+            (Value::String(a), Value::String(b)) => Ok(a <= b),
+
+            (a, b) => match (a.to_number(), b.to_number()) {
+                (Some(a), Some(b)) => Ok(Number::less_equal(a, b)),
+                _ => Err(self.compare_error(a, b))
+            }
+        }
     }
 
-    fn pop_num(&mut self) -> f64 {
-        match self.stack.pop().unwrap() {
-            Value::Number(x) => x,
+    fn compare_error(&self, left: &Value, right: &Value) -> Error {
+        let (a, b) = (left.type_name(), right.type_name());
 
-            _ => 0.0
+        if a == b {
+            self.error(format!("attempt to compare two {} values", a))
+        } else {
+            self.error(format!("attempt to compare {} with {}", a, b))
+        }
+    }
+
+    // pops two operands and pushes the result of a binary arithmetic operation
+    fn arith(&mut self, op: ArithOp) -> Result<()> {
+        let right = self.pop()?;
+        let left = self.pop()?;
+
+        // the left operand is blamed first
+        let (left, right) = match (left.to_arith_number(), right.to_arith_number()) {
+            (Some(left), Some(right)) => (left, right),
+            (None, _) => return Err(self.operand_error(
+                format!("attempt to perform arithmetic on a {} value", left.type_name()), 0
+            )),
+            (_, None) => return Err(self.operand_error(
+                format!("attempt to perform arithmetic on a {} value", right.type_name()), 1
+            ))
+        };
+
+        match Number::arith(op, left, right) {
+            Some(res) => {
+                self.stack.push(res.into());
+                Ok(())
+            },
+            None => Err(self.error(format!(
+                "attempt to perform 'n{}0'", if op == ArithOp::Mod { "%%" } else { "//" }
+            )))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::lang::{lexer::Lexer, parser::Parser, compiler::Compiler};
+    use crate::lang::{lexer::Lexer, parser::Parser, compiler::Compiler, token::{Location, Span}};
 
     use super::*;
 
+    #[test]
+    fn error_location() {
+        let toks = Lexer::new("a = 1\nb = a +\n  c.x").analyze().unwrap();
+        let ast = Parser::new(toks).parse().unwrap();
+        let co = Compiler::new().compile(&ast).unwrap();
+
+        let err = VirtualMachine::new(co).run().unwrap_err();
+
+        assert_eq!(err.loc(), Location { line: 3, column: 3, offset: 16 });
+    }
+
+    fn eval(src: &str) -> Result<Vec<Value>> {
+        let toks = Lexer::new(src).analyze()?;
+        let exprs = Parser::new(toks).parse_expr_list()?;
+        let co = Compiler::new().compile_expr_list(&exprs)?;
+
+        VirtualMachine::new(co).run()
+    }
+
+    // evaluates the expressions and gives their values as strings
+    fn eval_str(src: &str) -> Vec<String> {
+        eval(src).unwrap()
+            .iter()
+            .map(|v| v.to_string())
+            .collect()
+    }
+
+    // runs a chunk and gives the vm to look at its globals
+    fn exec(src: &str) -> Result<VirtualMachine> {
+        let toks = Lexer::new(src).analyze()?;
+        let ast = Parser::new(toks).parse()?;
+        let co = Compiler::new().compile(&ast)?;
+
+        let mut vm = VirtualMachine::new(co);
+        vm.run()?;
+
+        Ok(vm)
+    }
+
+    // gives the globals as strings, "nil" for the ones never set
+    fn globals(vm: &VirtualMachine, names: &[&str]) -> Vec<String> {
+        names.iter()
+            .map(|name| vm.memory.get(*name).map_or("nil".to_string(), |v| v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn number_format() {
+        assert_eq!(Value::Integer(3).to_string(), "3");
+        assert_eq!(Value::Number(3.0).to_string(), "3.0");
+        assert_eq!(Value::Number(1e15).to_string(), "1e+15");
+    }
+
+    #[test]
+    fn integer() {
+        let res = eval("1 + 2, 3 / 1, 7 // 2, 7.0 // 2, -7 % 3, 2 ^ 2, 9223372036854775807 + 1, 1 < 1.5")
+            .unwrap()
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(res, ["3", "3.0", "3", "3.0", "2", "4.0", "-9223372036854775808", "true"]);
+
+        let res = eval("tostring(3), tostring(3.0), tostring(-0.0), tostring(1 < 2)").unwrap();
+        assert!(res.iter().all(|v| matches!(v, Value::String(_))));
+        assert_eq!(res.iter().map(|v| v.to_string()).collect::<Vec<_>>(), ["3", "3.0", "-0.0", "true"]);
+
+        // not folded by the compiler, so the vm raises the error
+        assert_eq!(eval("1 // 0").unwrap_err().msg(), "attempt to perform 'n//0'");
+        assert_eq!(eval("1 % 0").unwrap_err().msg(), "attempt to perform 'n%%0'");
+    }
+
+    #[test]
+    fn unary() {
+        assert_eq!(eval_str("not false, not 0, -3, - -2.5, #'hello', #''"),
+            ["true", "false", "-3", "2.5", "5", "0"]);
+
+        assert_eq!(eval("#true").unwrap_err().msg(), "attempt to get length of a boolean value");
+    }
+
+    #[test]
+    fn concat() {
+        assert_eq!(eval_str("'a' .. 'b', 1 .. '', 1.0 .. '', 'x' .. 2 .. 3 .. 'y'"),
+            ["ab", "1", "1.0", "x23y"]);
+
+        assert_eq!(eval("'a' .. true").unwrap_err().msg(), "attempt to concatenate a boolean value");
+
+        // strings are bytes, whether they make up utf-8 or not
+        assert_eq!(eval_str("#('\\255' .. '\\xFF\\u{D800}'), '\\xFF' == '\\255', '\\xFF' > 'z', #'é'"),
+            ["5", "true", "true", "2"]);
+        assert!(matches!(&eval("'\\xFF' .. 1").unwrap()[..], [Value::String(x)] if x == b"\xFF1"));
+    }
+
+    #[test]
+    fn arith() {
+        assert_eq!(eval_str("5.5 % 2, -5 % 3, 5 % -3, 2 ^ 0.5, 3 - 1.5"),
+            ["1.5", "1", "-1", "1.4142135623731", "1.5"]);
+
+        // the exponent may be a unary expression, and '^' is right associative
+        assert_eq!(eval_str("2^-1, -2^2, 2^-3^2, 2^- -2, 2^#'abc'"),
+            ["0.5", "-4.0", "0.001953125", "4.0", "8.0"]);
+        assert_eq!(eval("2^not nil").unwrap_err().msg(), "attempt to perform arithmetic on a boolean value");
+
+        // strings holding a numeral are converted, keeping integers as integers
+        assert_eq!(eval_str("'10' + 1, '0x10' * '2', ' 1.5 ' - 1, -'2', 10 // '3.0'"),
+            ["11", "32", "0.5", "-2", "3.0"]);
+    }
+
+    #[test]
+    fn type_errors() {
+        let msg = |src| exec(src).err().unwrap().msg().to_string();
+
+        assert_eq!(msg("flag = true\nx = flag + 1"), "attempt to perform arithmetic on a boolean value (global 'flag')");
+        assert_eq!(msg("local t = {}\nx = 1 - t"), "attempt to perform arithmetic on a table value (local 't')");
+        assert_eq!(msg("local s = 'a'\nfunction f() return -s end\nf()"), "attempt to perform arithmetic on a string value (upvalue 's')");
+        assert_eq!(msg("t = {x = false}\ny = t.x * 2"), "attempt to perform arithmetic on a boolean value (field 'x')");
+        assert_eq!(msg("x = 'abc' / 2"), "attempt to perform arithmetic on a string value (constant 'abc')");
+        assert_eq!(msg("x = {} + {}"), "attempt to perform arithmetic on a table value");
+        assert_eq!(msg("t = {}\nx = 'a' .. t"), "attempt to concatenate a table value (global 't')");
+        assert_eq!(msg("x = #print"), "attempt to get length of a function value (global 'print')");
+        assert_eq!(msg("t = {}\nt.a.b = 1"), "attempt to index a nil value (field 'a')");
+        assert_eq!(msg("t = {}\nt.f()"), "attempt to call a nil value (field 'f')");
+
+        let err = exec("flag = true\nx = 2 *\n  flag").err().unwrap();
+        assert_eq!(err.loc(), Location { line: 2, column: 5, offset: 16 });
+    }
+
+    #[test]
+    fn compare() {
+        assert_eq!(eval_str("1 <= 1.0, 1 == 1.0, 'a' < 'b', 'b' <= 'a', 'a' == 'a', true == 1, print == print"),
+            ["true", "true", "true", "false", "true", "false", "true"]);
+
+        assert_eq!(eval("1 < 'x'").unwrap_err().msg(), "attempt to compare number with string");
+        assert_eq!(eval("true <= false").unwrap_err().msg(), "attempt to compare two boolean values");
+
+        assert_eq!(eval_str("2 > 1, 1 > 1, 1 >= 1.0, 'b' > 'a', 'a' >= 'ab', 1 ~= 1.0, 'a' ~= 'b', {} ~= {}"),
+            ["true", "false", "true", "true", "false", "false", "true", "true"]);
+        assert_eq!(eval_str("'Z' < 'a', '' < 'a', 'a\\0b' > 'a'"), ["true", "true", "true"]);
+
+        // the operands are evaluated from left to right, and reported in the swapped order
+        assert_eq!(eval("1 > 'x'").unwrap_err().msg(), "attempt to compare string with number");
+        assert_eq!(eval("{} >= 1").unwrap_err().msg(), "attempt to compare number with table");
+    }
+
+    #[test]
+    fn logic() {
+        assert_eq!(eval_str("1 and 2, false and 2, false or 'x', 3 or 4"),
+            ["2", "false", "x", "3"]);
+
+        // the right operand is not evaluated when the left one decides
+        assert_eq!(eval_str("false and undefined(), 1 or undefined(), true and false or 'b', 1 < 2 and 'a' or 'b'"),
+            ["false", "1", "b", "a"]);
+
+        let vm = exec("
+            local t = false
+            t = t or {}
+            t.n = (t.n or 0) + 1
+            n = t.n
+        ").unwrap();
+        assert_eq!(vm.memory["n"].to_string(), "1");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn long_chains() {
+        // a flat chain is compiled in a loop, however long it is
+        let sum = (1..20000).map(|i| format!(" + {}", i)).collect::<String>();
+        let vm = exec(&format!("
+            local a = {{}}
+            a.x = a
+            local function f() return f end
+            n = 1
+
+            x = 0{}
+            y = n{}
+            z = n{}
+            w = a{} == a
+            v = f{} == f
+        ", sum, " + n".repeat(20000), " and n".repeat(20000), ".x".repeat(20000), "()".repeat(20000))).unwrap();
+
+        assert!(matches!(vm.memory["x"], Value::Integer(199990000)));
+        assert!(matches!(vm.memory["y"], Value::Integer(20001)));
+        assert!(matches!(vm.memory["z"], Value::Integer(1)));
+        assert!(matches!(vm.memory["w"], Value::Boolean(true)));
+        assert!(matches!(vm.memory["v"], Value::Boolean(true)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn call() {
+        let vm = exec("
+            print('called', 1, 2.0)
+            x = print()
+        ").unwrap();
+
+        assert!(!vm.memory.contains_key("x"));
+        assert_eq!(exec("x = 1\nx()").err().unwrap().msg(), "attempt to call a number value (global 'x')");
+    }
+
+    #[test]
+    fn function() {
+        let vm = exec("
+            function fib(n)
+                if n < 2 then
+                    return n
+                end
+
+                return fib(n - 1) + fib(n - 2)
+            end
+
+            function first(a, b)
+                return a
+            end
+            function second(a, b)
+                return b
+            end
+            function none()
+            end
+            function swap(a, b)
+                return b, a
+            end
+
+            x = fib(20)
+            y = first(1, 2, 3)
+            z = second(1)
+            w = none()
+            n = 0
+
+            -- every result is kept, then adjusted to the number of targets
+            p, q = swap(1, 2)
+            r, s, t = swap(3)
+            u = swap(swap(4, 5))
+        ").unwrap();
+
+        assert!(matches!(vm.memory["x"], Value::Integer(6765)));
+        assert!(matches!(vm.memory["y"], Value::Integer(1)));
+        assert!(!vm.memory.contains_key("z"));
+        assert!(!vm.memory.contains_key("w"));
+
+        let values = globals(&vm, &["p", "q", "r", "s", "t", "u"]);
+        assert_eq!(values, ["2", "1", "nil", "3", "nil", "4"]);
+        assert!(vm.call_stack.is_empty());
+        assert_eq!(vm.stack.len(), 0);
+    }
+
+    #[test]
+    fn multiple_results() {
+        let vm = exec("
+            local function three() return 1, 2, 3 end
+            local function none() end
+
+            a, b = 1
+            c = 1, 2
+            d, e, f, g = 0, three()
+            h, i = three(), 10
+            j, k = (three())
+
+            -- the table and key of a target are evaluated before the values
+            local t = {}
+            local n = 1
+            n, t[n] = 2, 'x'
+            l = t[1]
+
+            m = #{three()} + #{three(), 4} + #{three(), none()}
+            o = #{math.type(three())} + #{three(), three()}
+
+            -- a call as the last argument passes all its results
+            local function pass() return three() end
+            local function second(x, y) return y end
+            p, q, r = pass()
+            s = none()
+            u = second(three())
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "o", "p", "q", "r", "s", "u"]);
+        assert_eq!(values, [
+            "1", "nil", "1", "0", "1", "2", "3", "1", "10", "1", "nil", "x", "6", "5", "1", "2", "3", "nil", "2"
+        ]);
+        assert!(vm.stack.is_empty());
+
+        // a parenthesized name is a value, not a variable
+        assert_eq!(exec("(a) = 1").err().unwrap().msg(), "syntax error near '='");
+    }
+
+    #[test]
+    fn varargs() {
+        let vm = exec("
+            local function count(...) return select('#', ...) end
+            local function pick(n, ...) return select(n, ...) end
+            local function first(a, ...) return a end
+
+            a = count() .. count(nil, nil) .. count(1, 2, 3)
+            b, c = pick(2, 'x', 'y', 'z')
+            d = pick(-1, 'x', 'y', 'z')
+            e = first(...)
+
+            -- the arguments can be forwarded and collected
+            local log = {}
+            local function wrap(f)
+                return function (...)
+                    log[#log + 1] = select('#', ...)
+                    return f(...)
+                end
+            end
+            local add = wrap(function (x, y) return x + y end)
+            f = add(1, 2) + add(3, 4, 5)
+            g = #log .. log[2]
+
+            local t = table.pack(1, nil, 3)
+            h = t.n
+            i, j, k = table.unpack({1, 2, 3}, 2)
+            l = #{table.unpack({1, 2, 3})}
+
+            local function sum(...)
+                local s = 0
+                for _, v in ipairs({...}) do
+                    s = s + v
+                end
+                return s, ...
+            end
+            m, n = sum(1, 2, 3)
+            o = (...)
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o"]);
+        assert_eq!(values, [
+            "023", "y", "z", "z", "nil", "10", "23", "3", "2", "3", "nil", "3", "6", "1", "nil"
+        ]);
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(exec("function f() return ... end").err().unwrap().msg(),
+            "cannot use '...' outside a vararg function near '...'");
+        assert_eq!(exec("select(0)").err().unwrap().msg(), "bad argument #1 to 'select' (index out of range)");
+        assert_eq!(exec("select('x')").err().unwrap().msg(), "bad argument #1 to 'select' (number expected, got string)");
+    }
+
+    #[test]
+    fn chunk_return() {
+        let toks = Lexer::new("
+            function f(a)
+                a = a * 2
+                return a, 0
+            end
+
+            return f(21), 'x'
+        ").analyze().unwrap();
+        let ast = Parser::new(toks).parse().unwrap();
+        let co = Compiler::new().compile(&ast).unwrap();
+
+        let res = VirtualMachine::new(co).run().unwrap();
+        assert_eq!(res.iter().map(|v| v.to_string()).collect::<Vec<_>>(), ["42", "x"]);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let err = exec("
+            function f()
+                return f() + 1
+            end
+
+            f()
+        ").err().unwrap();
+
+        assert_eq!(err.msg(), "stack overflow");
+    }
+
+    #[test]
+    fn local() {
+        let vm = exec("
+            local a, b, c = 1, 2
+            local d = 1, print()
+            x = a + b
+
+            local a = a + 10
+            if true then
+                local a = 100
+                local b = a
+                y = b
+            end
+
+            local function sum(n)
+                local s = 0
+                while 0 < n do
+                    local m = n
+                    s = s + m
+                    n = n - 1
+                end
+
+                return s
+            end
+
+            local _unused
+            z, w = a, c
+            v = sum(10)
+
+            -- a call at the end gives the values of the locals left
+            local function two() return 1, 2 end
+            local e, f = two()
+            u = e + f
+        ").unwrap();
+
+        assert!(matches!(vm.memory["x"], Value::Integer(3)));
+        assert!(matches!(vm.memory["y"], Value::Integer(100)));
+        assert!(matches!(vm.memory["z"], Value::Integer(11)));
+        assert!(!vm.memory.contains_key("w"));
+        assert!(matches!(vm.memory["v"], Value::Integer(55)));
+        assert!(matches!(vm.memory["u"], Value::Integer(3)));
+        assert!(!vm.memory.contains_key("a"));
+        assert!(vm.stack.is_empty());
+
+    }
+
+    #[test]
+    fn closure() {
+        let vm = exec("
+            local function counter()
+                local n = 0
+
+                local function inc()
+                    n = n + 1
+                    return n
+                end
+
+                return inc
+            end
+
+            local c1, c2 = counter(), counter()
+            c1()
+            c1()
+            a = c1()
+            b = c2()
+
+            -- two closures sharing one variable
+            local x = 1
+            function get() return x end
+            function set(v) x = v end
+            set(5)
+            c = get()
+
+            -- each iteration makes a new local
+            local i = 1
+            while i <= 3 do
+                local j = i
+                if j == 1 then function f1() return j end end
+                if j == 3 then function f3() return j end end
+                i = i + 1
+            end
+            d = f1() + f3() * 10
+
+            -- a closure reaching two levels up
+            function two_up(v)
+                local function deep()
+                    local function deeper() return v end
+                    return deeper()
+                end
+                return deep()
+            end
+            e = two_up('y')
+
+            local function fact(n)
+                if n < 2 then return 1 end
+                return n * fact(n - 1)
+            end
+            g = fact(10)
+        ").unwrap();
+
+        assert!(matches!(vm.memory["a"], Value::Integer(3)));
+        assert!(matches!(vm.memory["b"], Value::Integer(1)));
+        assert!(matches!(vm.memory["c"], Value::Integer(5)));
+        assert!(matches!(vm.memory["d"], Value::Integer(31)));
+        assert_eq!(vm.memory["e"].to_string(), "y");
+        assert!(matches!(vm.memory["g"], Value::Integer(3628800)));
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn anonymous_function() {
+        let vm = exec("
+            local function apply(f, x)
+                return f(x)
+            end
+
+            local function adder(n)
+                return function (x) return x + n end
+            end
+
+            local double = function (x) return x * 2 end
+            a = apply(double, 4)
+            b = apply(adder(10), 5)
+            c = apply(function (s) return s .. '!' end, 'hi')
+        ").unwrap();
+
+        assert!(matches!(vm.memory["a"], Value::Integer(8)));
+        assert!(matches!(vm.memory["b"], Value::Integer(15)));
+        assert_eq!(vm.memory["c"].to_string(), "hi!");
+
+        let err = exec("function a.b:c() end").err().unwrap();
+        assert_eq!(err.msg(), "attempt to index a nil value (global 'a')");
+
+        let err = exec("a = 1\nfunction a.b() end").err().unwrap();
+        assert_eq!(err.msg(), "attempt to index a number value (global 'a')");
+    }
+
+    #[test]
+    fn nil() {
+        assert_eq!(eval_str("nil, undefined, nil == false, not nil, undefined == nil"),
+            ["nil", "nil", "false", "true", "true"]);
+
+        let vm = exec("
+            x = 1
+            y = 2
+            x = nil
+            local t = {a = 1, b = nil}
+            t.a = nil
+            n = t.a == nil and t.b == nil
+        ").unwrap();
+
+        // a global set to nil is gone
+        assert!(!vm.memory.contains_key("x"));
+        assert!(matches!(vm.memory["y"], Value::Integer(2)));
+        assert!(matches!(vm.memory["n"], Value::Boolean(true)));
+
+        assert_eq!(exec("undefined()").err().unwrap().msg(), "attempt to call a nil value (global 'undefined')");
+    }
+
+    #[test]
+    fn table() {
+        let vm = exec("
+            local t = {1, 2, 3; x = 'x', ['y' .. 1] = 'y', [10] = 10, }
+            a = #t + t[10]
+            b = t.x .. t.y1
+
+            -- tables are shared
+            local u = t
+            u.z = {}
+            u.z.w = 5
+            c = t.z.w
+
+            t[4], t.x = 4, t.nothing
+            d = #t
+            e = t.x
+
+            -- the keys are evaluated before any assignment
+            local i = 1
+            i, t[i] = 2, 'one'
+            f = t[1]
+
+            local k = {}
+            t[k] = 'k'
+            t[1.0] = 'float'
+            g = t[k] .. t[1]
+
+            h = math.type(1) .. math.type(1.0) .. #{n = 1}
+
+            function t.m() return 'm' end
+            m = t.m()
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b", "c", "d", "e", "f", "g", "h", "m"]);
+        assert_eq!(values, ["13", "xy", "5", "4", "nil", "one", "kfloat", "integerfloat0", "m"]);
+
+        assert_eq!(exec("x = 1\ny = x.a").err().unwrap().msg(), "attempt to index a number value (global 'x')");
+        assert_eq!(exec("t = {}\nt[t.x] = 1").err().unwrap().msg(), "index is nil");
+    }
+
+    #[test]
+    fn free_chain() {
+        // long chains of tables and functions are freed without recursing
+        exec("local a = {} for i = 1, 200000 do a = {a} end a = nil").unwrap();
+        exec("local f for i = 1, 200000 do local g = f f = function () return g end end").unwrap();
+    }
+
+    #[test]
+    fn collect_cycles() {
+        let vm = exec("
+            local t = {}
+            t.self = t
+            local function fact(n)
+                if n < 2 then return 1 end
+                return n * fact(n - 1)
+            end
+            fact(5)
+
+            kept = {}
+            kept.self = kept
+        ").unwrap();
+
+        // the table and the function with its upvalue, the global is still used
+        assert_eq!(gc::collect(), 3);
+        assert!(matches!(vm.memory["kept"].clone(), Value::Table(t) if matches!(t.borrow().get(&"self".into()), Value::Table(_))));
+
+        drop(vm);
+        assert_eq!(gc::collect(), 1);
+    }
+
+    #[test]
+    fn method() {
+        let vm = exec("
+            local Account = {}
+
+            function Account.new(balance)
+                return { balance = balance, deposit = Account.deposit, get = Account.get }
+            end
+            function Account:deposit(v)
+                self.balance = self.balance + v
+            end
+            function Account:get()
+                return self.balance
+            end
+
+            local count = 0
+            local function make()
+                count = count + 1
+                return Account.new(100)
+            end
+
+            local acc = make()
+            acc:deposit(50)
+            a = acc:get()
+
+            -- the object is evaluated once
+            b = make():get() + count
+        ").unwrap();
+
+        assert!(matches!(vm.memory["a"], Value::Integer(150)));
+        assert!(matches!(vm.memory["b"], Value::Integer(102)));
+
+        assert_eq!(exec("t = {}\nt:m()").err().unwrap().msg(), "attempt to call a nil value (method 'm')");
+    }
+
+    #[test]
+    fn numeric_for() {
+        let vm = exec("
+            local function count(a, b, c)
+                local n, last = 0, 'none'
+                for i = a, b, c do
+                    n = n + 1
+                    last = i
+                end
+                return n .. ' ' .. last
+            end
+
+            a = count(1, 10, 1)
+            b = count(10, 1, -3)
+            c = count(1, 0, 1)
+            d = count(1, 2.5, 1)
+            e = count(0.5, 1.5, 0.25)
+            f = count(9223372036854775805, 9223372036854775807, 1)
+            g = count(-9223372036854775807 - 1, 0, 9223372036854775807)
+
+            -- the limit is evaluated once and the variable is a copy
+            local limit = 3
+            h = 0
+            for i = 1, limit do
+                limit = 10
+                i = i * 10
+                h = h + i
+            end
+
+            -- each iteration has its own variable
+            local fs = {}
+            for i = 1, 3 do
+                fs[i] = function () return i end
+            end
+            k = fs[1]() + fs[3]()
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b", "c", "d", "e", "f", "g", "h", "k"]);
+        assert_eq!(values, [
+            "10 10", "4 1", "0 none", "2 2", "5 1.5",
+            "3 9223372036854775807", "2 -1", "60", "4"
+        ]);
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(exec("for i = 1, 10, 0 do end").err().unwrap().msg(), "'for' step is zero");
+        assert_eq!(exec("for i = 1, {} do end").err().unwrap().msg(), "'for' limit must be a number");
+    }
+
+    #[test]
+    fn generic_for() {
+        let vm = exec("
+            local t = {10, 20, 30, x = 1, y = 2}
+
+            a = 0
+            for i, v in ipairs(t) do
+                a = a + i * v
+            end
+
+            b, c = 0, 0
+            for k, v in pairs(t) do
+                b = b + v
+                c = c + 1
+            end
+
+            -- a closure keeping its own state
+            local function range(n)
+                local i = 0
+                return function ()
+                    if i < n then
+                        i = i + 1
+                        return i
+                    end
+                end
+            end
+            d = 0
+            for i in range(4) do
+                d = d + i
+            end
+
+            -- false is a valid control variable, only nil ends the loop
+            local function flip(_, last)
+                if last == true then return false end
+                if last == false then return end
+                return true
+            end
+            e = 0
+            for v in flip, 0, 0 do
+                e = e + 1
+            end
+
+            -- each iteration has its own variables
+            local fs = {}
+            for i, v in ipairs(t) do
+                fs[i] = function () return v end
+            end
+            f = fs[1]() + fs[3]()
+
+            -- clearing the fields while traversing them
+            for k in pairs(t) do
+                t[k] = nil
+            end
+            g = next(t) == nil and #t
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b", "c", "d", "e", "f", "g"]);
+        assert_eq!(values, ["140", "63", "5", "10", "2", "40", "0"]);
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(exec("for k in pairs(1) do end").err().unwrap().msg(), "bad argument #1 to 'pairs' (table expected, got number)");
+        assert_eq!(exec("for k in 1 do end").err().unwrap().msg(), "attempt to call a number value");
+    }
+
+    #[test]
+    fn repeat_and_break() {
+        let vm = exec("
+            -- the condition sees the locals of the body
+            a = 0
+            repeat
+                local done = 3 <= a
+                a = a + 1
+            until done
+
+            b = 0
+            while true do
+                b = b + 1
+                if b == 5 then break end
+            end
+
+            -- a break leaves the innermost loop, dropping its locals
+            c = 0
+            for i = 1, 3 do
+                for j, v in ipairs({1, 2, 3}) do
+                    local x = i * v
+                    if j == 2 then break end
+                    c = c + x
+                end
+            end
+
+            -- the closures made before the break keep their values
+            local fs = {}
+            repeat
+                local i = 1
+                fs[1] = function () return i end
+                i = 2
+                do
+                    local y = 10
+                    break
+                end
+            until false
+            d = fs[1]()
+
+            do
+                local z = 7
+                e = z
+            end
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b", "c", "d", "e"]);
+        assert_eq!(values, ["4", "5", "6", "2", "7"]);
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(exec("if true then break end").err().unwrap().msg(), "break outside a loop at line 1");
+    }
+
+    #[test]
+    fn goto() {
+        let vm = exec("
+            -- a loop made of gotos, each pass has its own local
+            local fs = {}
+            local i = 1
+            ::top::
+            local x = i * 10
+            fs[i] = function () return x end
+            i = i + 1
+            if i <= 3 then goto top end
+            a = fs[1]() + fs[3]()
+
+            -- a label at the end of a block is out of the scope of its locals
+            b = 0
+            for i = 1, 5 do
+                if i % 2 == 0 then goto continue end
+                local y = i
+                b = b + y
+                ::continue::
+            end
+
+            do
+                goto skip
+                c = 1
+            end
+            ::skip::
+        ").unwrap();
+
+        let values = globals(&vm, &["a", "b"]);
+        assert_eq!(values, ["40", "9"]);
+        assert!(!vm.memory.contains_key("c"));
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(exec("goto nowhere").err().unwrap().msg(), "no visible label 'nowhere' for <goto> at line 1");
+        assert_eq!(exec("::a:: do ::a:: end").err().unwrap().msg(), "label 'a' already defined on line 1");
+        assert_eq!(
+            exec("goto a\nlocal x = 1\n::a::\nx = 2").err().unwrap().msg(),
+            "<goto a> at line 1 jumps into the scope of local 'x'"
+        );
+        // labels are not visible in nested functions
+        assert!(exec("::a:: local function f() goto a end").is_err());
+    }
+
+    #[test]
+    fn locals() {
+        use super::super::bytecode::{Bytecode, Instruction};
+
+        // one local in slot 0, set to 1 and then to itself plus 1
+        let code = |inst, arg| Bytecode { inst, arg };
+        let bc = vec![
+            code(Instruction::LoadNil, 0),
+            code(Instruction::LoadNumber, 0),
+            code(Instruction::StoreLocal, 0),
+            code(Instruction::LoadLocal, 0),
+            code(Instruction::LoadNumber, 0),
+            code(Instruction::BinAdd, 0),
+            code(Instruction::StoreLocal, 0),
+            code(Instruction::LoadLocal, 0),
+            code(Instruction::Return, 1),
+        ];
+        let co = Bytecodes {
+            spans: vec![Span::default(); bc.len()],
+            bc,
+            nums: vec![Number::Integer(1)],
+            strs: vec![],
+            idents: vec![],
+            protos: vec![],
+            params: 0,
+            is_vararg: false,
+            upvalues: vec![],
+            names: HashMap::new()
+        };
+
+        let res = VirtualMachine::new(co).run().unwrap();
+        assert!(matches!(res[..], [Value::Integer(2)]));
+    }
+
     #[test]
     fn see() {
         let toks = Lexer::new("
@@ -166,9 +1760,9 @@ mod tests {
                 d = d + i
                 i = i + 1
             end
-        ").analyze();
-        let ast = Parser::new(toks).parse();
-        let co = Compiler::new().compile(&ast);
+        ").analyze().unwrap();
+        let ast = Parser::new(toks).parse().unwrap();
+        let co = Compiler::new().compile(&ast).unwrap();
         
         for (i, v) in co.bc.iter().enumerate() {
             println!("{}: {:?}", i, v);
@@ -176,6 +1770,6 @@ mod tests {
 
         let mut vm = VirtualMachine::new(co);
 
-        vm.run();
+        vm.run().unwrap();
     }
 }
\ No newline at end of file
@@ -1,3 +1,82 @@
+mod repl;
+
+use std::{env, ffi::OsString, fs, io::{self, Read}, os::unix::ffi::OsStringExt, process};
+
+use rua::lang::{lexer::Lexer, parser::Parser, compiler::Compiler, vm::{VirtualMachine, Value}, table::Table, error::Error};
+
 fn main() {
-    println!("Hello, world!");
+    // the arguments need not be valid utf-8, lua takes them as bytes
+    let mut args = env::args_os();
+    let prog = args.next().unwrap_or_else(|| "rua".into());
+
+    // without a script, start the interactive mode
+    let path = match args.next() {
+        Some(path) => path,
+        None => {
+            repl::run();
+            return;
+        }
+    };
+
+    let src = match read_source(&path) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("rua: cannot open {}: {}", path.to_string_lossy(), e);
+            process::exit(1);
+        }
+    };
+
+    let args = args.map(|a| Value::String(a.into_vec())).collect::<Vec<_>>();
+
+    if let Err(e) = run(&src, script_arg(prog, path.clone(), &args), args) {
+        eprintln!("rua: {}", e.render(&chunk_name(&path), &String::from_utf8_lossy(&src)));
+        process::exit(1);
+    }
+}
+
+// the script is read as bytes, the lexer takes care of invalid utf-8
+fn read_source(path: &OsString) -> io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut src = vec![];
+        io::stdin().read_to_end(&mut src)?;
+
+        Ok(src)
+    } else {
+        fs::read(path)
+    }
+}
+
+fn chunk_name(path: &OsString) -> String {
+    if path == "-" {
+        "stdin".to_string()
+    } else {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+// the table `arg` of the script, as made by the lua interpreter: the
+// interpreter is at -1, the script at 0 and its arguments from 1 on
+fn script_arg(prog: OsString, path: OsString, args: &[Value]) -> Table {
+    let mut arg = Table::new();
+    arg.set(Value::Integer(-1), Value::String(prog.into_vec())).unwrap();
+    arg.set(Value::Integer(0), Value::String(path.into_vec())).unwrap();
+    for (i, a) in args.iter().enumerate() {
+        arg.set(Value::Integer(i as i64 + 1), a.clone()).unwrap();
+    }
+
+    arg
+}
+
+// the arguments are also given to the chunk as `...`
+fn run(src: &[u8], arg: Table, args: Vec<Value>) -> Result<(), Error> {
+    let toks = Lexer::new(src).analyze()?;
+    let ast = Parser::new(toks).parse()?;
+    let codes = Compiler::new().compile(&ast)?;
+
+    let mut vm = VirtualMachine::new(codes);
+    vm.set_global("arg", arg.into());
+    vm.set_varargs(args);
+    vm.run()?;
+
+    Ok(())
 }
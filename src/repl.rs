@@ -0,0 +1,100 @@
+use std::io::{self, BufRead, Write};
+
+use rua::lang::{lexer::Lexer, parser::Parser, compiler::Compiler, bytecode::Bytecodes, vm::VirtualMachine, error::Error};
+
+enum Input {
+    Complete(Bytecodes),
+    // the input ended in the middle of a statement
+    Incomplete,
+    Invalid(Error)
+}
+
+pub fn run() {
+    // one vm for the whole session, so the globals live across inputs
+    let mut vm = VirtualMachine::new(Compiler::new().compile(&vec![]).unwrap());
+    let mut buf = String::new();
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("{}", if buf.is_empty() { "> " } else { ">> " });
+        io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => {
+                println!();
+                break;
+            }
+        };
+
+        buf.push_str(&line);
+        buf.push('\n');
+
+        match compile(&buf) {
+            Input::Complete(codes) => {
+                let src = std::mem::take(&mut buf);
+
+                vm.load(codes);
+                match vm.run() {
+                    Ok(values) => {
+                        if !values.is_empty() {
+                            let mut line = values.iter()
+                                .map(|v| v.to_bytes())
+                                .collect::<Vec<_>>()
+                                .join(&b'\t');
+                            line.push(b'\n');
+
+                            io::stdout().write_all(&line).unwrap();
+                        }
+                    },
+                    Err(e) => eprintln!("rua: {}", e.render("stdin", &src))
+                }
+            },
+            Input::Incomplete => continue,
+            Input::Invalid(e) => {
+                eprintln!("rua: {}", e.render("stdin", &buf));
+
+                buf.clear();
+            }
+        }
+    }
+}
+
+fn compile(src: &str) -> Input {
+    let toks = match Lexer::new(src).analyze() {
+        Ok(toks) => toks,
+        // a long string or comment may go on in the next lines
+        Err(Error::Lex { msg, .. }) if msg == "unfinished long string" || msg == "unfinished long comment" => {
+            return Input::Incomplete;
+        },
+        Err(e) => return Input::Invalid(e)
+    };
+
+    // a bare expression is compiled so that the vm leaves its values behind,
+    // one cut short like `1 +` waits for the rest, unless the input is blank
+    let mut parser = Parser::new(toks.clone());
+    match parser.parse_expr_list() {
+        Ok(exprs) => {
+            return match Compiler::new().compile_expr_list(&exprs) {
+                Ok(codes) => Input::Complete(codes),
+                Err(e) => Input::Invalid(e)
+            };
+        },
+        Err(_) if parser.at_eof() && toks.len() > 1 => return Input::Incomplete,
+        Err(_) => {}
+    }
+
+    let mut parser = Parser::new(toks);
+    let ast = match parser.parse() {
+        Ok(ast) => ast,
+        Err(_) if parser.at_eof() => return Input::Incomplete,
+        Err(e) => return Input::Invalid(e)
+    };
+
+    match Compiler::new().compile(&ast) {
+        Ok(codes) => Input::Complete(codes),
+        Err(e) => Input::Invalid(e)
+    }
+}